@@ -1,4 +1,3 @@
-use proc_macro;
 use proc_macro2::TokenStream;
 use quote::quote;
 use syn::punctuated::Punctuated;
@@ -6,57 +5,169 @@ use syn::token::Comma;
 use syn::{parse_macro_input, DeriveInput, Ident, Lit, Meta, NestedMeta, Variant};
 
 // enum Typ {
-//     #[boi_typ(code = 0b00, use_channels, size = 3)]
+//     #[boi(code = 0b00, code_len = 2, size = 3, uses_channels = true)]
 //     Short,
 // }
 //
-// impl From<usize> for Typ {
-//     fn from(code: usize) -> Self {
-//         match code {
-//             0b00 => Self::Short,
-//             0b01 => Self::Medium,
-//             0b11 => Self::Long,
-//             _ => panic!("The block with the code `{code}` does not exists"),
-//         }
-//     }
-// }
+// Every type of block is described by its prefix code, the length of the code and the size of its
+// payload, per channel when it uses the channels. The derive generates:
 //
-// impl Typ {
-//     pub fn size(&self) -> usize {
-//         match code {
-//             0b00 => Self::Short,
-//             0b01 => Self::Medium,
-//             0b11 => Self::Long,
-//             _ => panic!("The block with the code `{code}` does not exists"),
-//         }
-//     }
-// }
+// - the constants `SHORT_CODE`, `SHORT_CODE_LEN` and `SHORT_SIZE` of each type,
+// - `CODES`, the codes in the order of the variants,
+// - `MAX_CODE_LEN` and `CODE_LOOKUP`, the code and the length of the code starting each value of
+//   `MAX_CODE_LEN` bits,
+// - `KRAFT_SLACK`, the room left for more codes,
+// - `code`, `size`, `from_code`, `code_len`, `block_len` and `fields`,
+// - `TryFrom<usize>`, the type of a code.
+//
+// The enum must be generic over `const CHANNELS: usize` and nothing else. `code`, `code_len` and
+// `size` are required, `uses_channels` is false by default. The codes must fit in their length and
+// no code can start with another one, the decoder could not tell them apart otherwise. The derive fails with an error pointing at the malformed attribute or at the
+// variant breaking these rules.
+
+/// The longest code, the decoding table has an entry per value of that many bits.
+const MAX_CODE_LEN: usize = 16;
 
 #[proc_macro_derive(BoiTyp, attributes(boi))]
 pub fn boi_typ_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let name = input.ident;
-    let (code_body, size_body, consts) = match input.data {
-        syn::Data::Enum(data) => parse_variants(data.variants),
-        _ => panic!("Should be an enum"),
+    let data = match input.data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            let error = syn::Error::new_spanned(&name, "`BoiTyp` can only be derived for enums");
+            return error.to_compile_error().into();
+        }
     };
+    if !has_channels(&input.generics) {
+        let error = syn::Error::new_spanned(
+            &name,
+            "`BoiTyp` can only be derived for enums generic over `const CHANNELS: usize`",
+        );
+        return error.to_compile_error().into();
+    }
+    let mut errors = Errors::default();
+    let typs = parse_variants(&data.variants, &mut errors);
+    // The codes are only checked once the attributes are valid.
+    let kraft_slack = match errors.0 {
+        Some(_) => 0,
+        None => validate(&typs, &mut errors),
+    };
+    // The items are still generated next to the errors, the rest of the crate would fail too
+    // otherwise.
+    let errors = errors.into_compile_error();
+
+    let consts = typs.iter().map(BlockTyp::consts);
+    let idents = typs.iter().map(|typ| &typ.ident).collect::<Vec<_>>();
+    let codes = typs.iter().map(|typ| typ.code).collect::<Vec<_>>();
+    let code_lens = typs.iter().map(|typ| typ.code_len).collect::<Vec<_>>();
+    let sizes = typs.iter().map(|typ| typ.size).collect::<Vec<_>>();
+    let counts = typs
+        .iter()
+        .map(|typ| match typ.uses_channels {
+            true => quote! { CHANNELS },
+            false => quote! { 1 },
+        })
+        .collect::<Vec<_>>();
+    let typs_count = typs.len();
+    let max_code_len = code_lens
+        .iter()
+        .copied()
+        .filter(|code_len| *code_len <= MAX_CODE_LEN)
+        .max()
+        .unwrap_or(0);
+    let lookup = (0..1usize << max_code_len).map(|bits| {
+        let typ = typs.iter().find(|typ| {
+            typ.code_len <= max_code_len && bits >> (max_code_len - typ.code_len) == typ.code
+        });
+        let (code, code_len) = typ.map_or((0, 0), |typ| (typ.code, typ.code_len));
+        quote! { (#code, #code_len) }
+    });
 
     let expanded = quote! {
+        #errors
+
         impl<const CHANNELS: usize> #name<CHANNELS> {
-            #consts
+            #(#consts)*
+
+            /// The codes of every type of block, in the order of the variants.
+            pub const CODES: [usize; #typs_count] = [#(#codes),*];
 
+            /// The length of the longest code.
+            pub const MAX_CODE_LEN: usize = #max_code_len;
+
+            /// The Kraft-inequality slack of the codes, in units of `2^-MAX_CODE_LEN`: the number of
+            /// values of `MAX_CODE_LEN` bits that start with no code. The codes are complete when
+            /// it is 0, a new type of block needs some slack.
+            pub const KRAFT_SLACK: usize = #kraft_slack;
+
+            /// The code and the length of the code that starts each value of `MAX_CODE_LEN` bits,
+            /// `(0, 0)` when no code does.
+            pub const CODE_LOOKUP: [(usize, usize); 1 << #max_code_len] = [#(#lookup),*];
+
+            /// Return the prefix code of the type.
+            #[inline]
+            pub fn code(&self) -> usize {
+                match self {
+                    #(Self::#idents => #codes),*
+                }
+            }
+
+            /// Return the size of a block of the type, with its code.
             pub fn size(&self) -> usize {
                 match self {
-                    #size_body
+                    #(Self::#idents => #counts * #sizes + #code_lens),*
+                }
+            }
+
+            /// Return the type of the prefix code `code` of `code_len` bits, if any.
+            #[inline]
+            pub fn from_code(code: usize, code_len: usize) -> Option<Self> {
+                match (code, code_len) {
+                    #((#codes, #code_lens) => Some(Self::#idents),)*
+                    _ => None,
+                }
+            }
+
+            /// Return the number of bits of the prefix code `code`, 0 for an unknown code.
+            #[inline]
+            pub fn code_len(code: usize) -> usize {
+                match code {
+                    #(#codes => #code_lens,)*
+                    _ => 0,
+                }
+            }
+
+            /// Return the number of bits of the payload following the prefix code `code`, 0 for an
+            /// unknown code.
+            #[inline]
+            pub fn block_len(code: usize) -> usize {
+                match code {
+                    #(#codes => #counts * #sizes,)*
+                    _ => 0,
+                }
+            }
+
+            /// Return how the payload following the prefix code `code` is split into fields: the
+            /// number of bits of a field and the number of fields. The payload of the types using
+            /// the channels has a field per channel, the others are a single field.
+            #[inline]
+            pub fn fields(code: usize) -> (usize, usize) {
+                match code {
+                    #(#codes => (#sizes, #counts),)*
+                    _ => (0, 1),
                 }
             }
         }
 
-        impl<const CHANNELS: usize> From<usize> for #name<CHANNELS> {
-            fn from(code: usize) -> Self {
+        impl<const CHANNELS: usize> TryFrom<usize> for #name<CHANNELS> {
+            type Error = usize;
+
+            /// Return the type of the prefix code `code`, or the code itself if it is unknown.
+            fn try_from(code: usize) -> Result<Self, usize> {
                 match code {
-                    #code_body,
-                    _ => panic!("The code does not exists")
+                    #(#codes => Ok(Self::#idents),)*
+                    _ => Err(code),
                 }
             }
         }
@@ -64,87 +175,255 @@ pub fn boi_typ_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream
     proc_macro::TokenStream::from(expanded)
 }
 
-fn parse_variants(variants: Punctuated<Variant, Comma>) -> (TokenStream, TokenStream, TokenStream) {
-    let mut attrs = Vec::new();
-    for variant in variants.iter() {
-        for attr in variant.attrs.iter() {
-            if attr.path.is_ident("boi") {
-                attrs.push((variant.ident.clone(), attr.parse_meta()));
-            }
+/// Return whether the only generic parameter is `const CHANNELS: usize`, the generated impls
+/// are written for it.
+fn has_channels(generics: &syn::Generics) -> bool {
+    let mut params = generics.params.iter();
+    match (params.next(), params.next()) {
+        (Some(syn::GenericParam::Const(param)), None) => {
+            param.ident == "CHANNELS"
+                && matches!(&param.ty, syn::Type::Path(ty) if ty.path.is_ident("usize"))
+        }
+        _ => false,
+    }
+}
+
+/// A variant of the enum and its `#[boi(...)]` attribute.
+struct BlockTyp {
+    ident: Ident,
+    code: usize,
+    code_len: usize,
+    size: usize,
+    uses_channels: bool,
+}
+
+impl BlockTyp {
+    /// The type of a variant whose attribute is malformed, its items are still generated.
+    fn invalid(ident: &Ident) -> Self {
+        Self {
+            ident: ident.clone(),
+            code: 0,
+            code_len: 0,
+            size: 0,
+            uses_channels: false,
         }
     }
 
-    let mut code_bodies = Vec::new();
-    let mut size_bodies = Vec::new();
-    let mut consts: Vec<TokenStream> = Vec::new();
-    for (ident, meta) in attrs.iter() {
+    fn consts(&self) -> TokenStream {
+        let ident = &self.ident;
         let upper_ident = &ident.to_string().to_uppercase();
         let const_code_ident = Ident::new(&format!("{upper_ident}_CODE"), ident.span());
         let const_code_len_ident = Ident::new(&format!("{upper_ident}_CODE_LEN"), ident.span());
         let const_size_ident = Ident::new(&format!("{upper_ident}_SIZE"), ident.span());
-        if let Ok(meta) = meta {
-            match meta {
-                Meta::List(meta_list) => {
-                    let (is_using_channels, code, code_len, size) = parse_attrs(meta_list);
-                    consts.push(quote! {
-                        const #const_code_ident: usize = #code;
-                        const #const_code_len_ident: usize = #code_len;
-                        const #const_size_ident: usize = #size;
-                    });
-                    code_bodies.push(quote! {
-                        #code => Self::#ident
-                    });
-                    if is_using_channels {
-                        size_bodies.push(quote! {
-                            Self::#ident => CHANNELS * #size + #code_len
-                        });
-                    } else {
-                        size_bodies.push(quote! {
-                            Self::#ident => #size + #code_len
-                        });
-                    }
-                }
-                _ => {}
-            }
+        let (code, code_len, size) = (self.code, self.code_len, self.size);
+        quote! {
+            const #const_code_ident: usize = #code;
+            const #const_code_len_ident: usize = #code_len;
+            const #const_size_ident: usize = #size;
         }
     }
-    (
-        quote! { #(#code_bodies),* },
-        quote! { #(#size_bodies),* },
-        quote! { #(#consts)* },
-    )
 }
 
-fn parse_attrs(meta_list: &syn::MetaList) -> (bool, usize, usize, usize) {
-    let mut code = 0;
-    let mut size = 0;
-    let mut code_len = 0;
-    let mut is_using_channels = false;
-    meta_list.nested.iter().for_each(|nested| match nested {
-        NestedMeta::Meta(m) => match m {
-            Meta::NameValue(name_value) => {
-                match &name_value.lit {
-                    Lit::Int(int) => {
-                        let value = int.base10_parse::<usize>().unwrap();
-                        if name_value.path.is_ident("code") {
-                            code = value;
-                        } else if name_value.path.is_ident("size") {
-                            size = value;
-                        } else if name_value.path.is_ident("code_len") {
-                            code_len = value;
-                        }
-                    }
-                    Lit::Bool(_) => {
-                        if name_value.path.is_ident("uses_channels") {
-                            is_using_channels = true;
-                        }
-                    }
-                    _ => {}
+/// The errors found in the input, they are all reported at once.
+#[derive(Default)]
+struct Errors(Option<syn::Error>);
+
+impl Errors {
+    fn push(&mut self, error: syn::Error) {
+        match &mut self.0 {
+            Some(errors) => errors.combine(error),
+            None => self.0 = Some(error),
+        }
+    }
+
+    fn into_compile_error(self) -> TokenStream {
+        self.0
+            .map_or_else(TokenStream::new, |errors| errors.to_compile_error())
+    }
+}
+
+/// Check that the codes fit in their length and form a prefix code, and return the Kraft-inequality
+/// slack of the codes, see `KRAFT_SLACK`.
+fn validate(typs: &[BlockTyp], errors: &mut Errors) -> usize {
+    let mut push_error =
+        |ident: &Ident, message: String| errors.push(syn::Error::new(ident.span(), message));
+    for (idx, typ) in typs.iter().enumerate() {
+        let ident = &typ.ident;
+        if typ.code_len == 0 || typ.code_len > MAX_CODE_LEN {
+            push_error(
+                ident,
+                format!("the code of `{ident}` must be 1 to {MAX_CODE_LEN} bits long"),
+            );
+            continue;
+        }
+        if typ.code >> typ.code_len != 0 {
+            push_error(
+                ident,
+                format!(
+                    "the code {:#b} of `{ident}` does not fit in {} bits",
+                    typ.code, typ.code_len
+                ),
+            );
+            continue;
+        }
+        let valid = |other: &&BlockTyp| {
+            (1..=MAX_CODE_LEN).contains(&other.code_len) && other.code >> other.code_len == 0
+        };
+        for other in typs[..idx].iter().filter(valid) {
+            let (short, long) = match other.code_len <= typ.code_len {
+                true => (other, typ),
+                false => (typ, other),
+            };
+            if long.code >> (long.code_len - short.code_len) == short.code {
+                let relation = match short.code_len == long.code_len {
+                    true => "is the same as",
+                    false => "starts with",
                 };
+                push_error(
+                    ident,
+                    format!(
+                        "the code {} of `{}` {relation} the code {} of `{}`",
+                        binary(long.code, long.code_len),
+                        long.ident,
+                        binary(short.code, short.code_len),
+                        short.ident,
+                    ),
+                );
             }
-            _ => unreachable!(),
-        },
-        _ => {}
-    });
-    (is_using_channels, code, code_len, size)
+        }
+    }
+    if errors.0.is_some() {
+        return 0;
+    }
+    let max_code_len = typs.iter().map(|typ| typ.code_len).max().unwrap_or(0);
+    let used = typs
+        .iter()
+        .map(|typ| 1usize << (max_code_len - typ.code_len))
+        .sum::<usize>();
+    (1 << max_code_len) - used
+}
+
+/// Format `code` in binary with its leading zeros.
+fn binary(code: usize, code_len: usize) -> String {
+    format!("{code:#0width$b}", width = code_len + 2)
+}
+
+fn parse_variants(variants: &Punctuated<Variant, Comma>, errors: &mut Errors) -> Vec<BlockTyp> {
+    let mut typs = Vec::new();
+    for variant in variants.iter() {
+        let mut attrs = variant
+            .attrs
+            .iter()
+            .filter(|attr| attr.path.is_ident("boi"));
+        let Some(attr) = attrs.next() else {
+            errors.push(syn::Error::new_spanned(
+                &variant.ident,
+                format!(
+                    "`{}` needs a `#[boi(code = .., code_len = .., size = ..)]` attribute",
+                    variant.ident
+                ),
+            ));
+            typs.push(BlockTyp::invalid(&variant.ident));
+            continue;
+        };
+        for duplicate in attrs {
+            errors.push(syn::Error::new_spanned(
+                duplicate,
+                "duplicate `#[boi(...)]` attribute",
+            ));
+        }
+        let typ = match attr.parse_meta() {
+            Ok(Meta::List(meta_list)) => parse_attrs(&variant.ident, &meta_list, errors),
+            Ok(meta) => {
+                errors.push(syn::Error::new_spanned(
+                    meta,
+                    "expected `#[boi(code = .., code_len = .., size = ..)]`",
+                ));
+                BlockTyp::invalid(&variant.ident)
+            }
+            Err(error) => {
+                errors.push(error);
+                BlockTyp::invalid(&variant.ident)
+            }
+        };
+        typs.push(typ);
+    }
+    typs
+}
+
+fn parse_attrs(ident: &Ident, meta_list: &syn::MetaList, errors: &mut Errors) -> BlockTyp {
+    let mut code = None;
+    let mut code_len = None;
+    let mut size = None;
+    let mut uses_channels = None;
+    // The keys given, even with a wrong value.
+    let mut keys = Vec::new();
+    for nested in meta_list.nested.iter() {
+        let name_value = match nested {
+            NestedMeta::Meta(Meta::NameValue(name_value)) => name_value,
+            _ => {
+                errors.push(syn::Error::new_spanned(nested, "expected `key = value`"));
+                continue;
+            }
+        };
+        let (path, lit) = (&name_value.path, &name_value.lit);
+        let key = path.get_ident().map(|ident| ident.to_string());
+        keys.extend(key.clone());
+        let result = match key.as_deref() {
+            Some("code") => set(&mut code, path, parse_int(lit)),
+            Some("code_len") => set(&mut code_len, path, parse_int(lit)),
+            Some("size") => set(&mut size, path, parse_int(lit)),
+            Some("uses_channels") => set(&mut uses_channels, path, parse_bool(lit)),
+            _ => Err(syn::Error::new_spanned(
+                path,
+                "unknown key, expected `code`, `code_len`, `size` or `uses_channels`",
+            )),
+        };
+        if let Err(error) = result {
+            errors.push(error);
+        }
+    }
+    for key in ["code", "code_len", "size"] {
+        if !keys.iter().any(|given| given == key) {
+            errors.push(syn::Error::new_spanned(
+                meta_list,
+                format!("missing `{key} = ..`"),
+            ));
+        }
+    }
+    BlockTyp {
+        ident: ident.clone(),
+        code: code.unwrap_or(0),
+        code_len: code_len.unwrap_or(0),
+        size: size.unwrap_or(0),
+        uses_channels: uses_channels.unwrap_or(false),
+    }
+}
+
+/// Parse the value of an integer key.
+fn parse_int(lit: &Lit) -> syn::Result<usize> {
+    match lit {
+        Lit::Int(int) => int
+            .base10_parse::<usize>()
+            .map_err(|_| syn::Error::new_spanned(lit, "expected an unsigned integer")),
+        _ => Err(syn::Error::new_spanned(lit, "expected an unsigned integer")),
+    }
+}
+
+/// Store the value of the key `path`, which can only be given once.
+fn set<T>(slot: &mut Option<T>, path: &syn::Path, value: syn::Result<T>) -> syn::Result<()> {
+    let value = value?;
+    if slot.is_some() {
+        return Err(syn::Error::new_spanned(path, "duplicate key"));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+/// Parse the value of a boolean key.
+fn parse_bool(lit: &Lit) -> syn::Result<bool> {
+    match lit {
+        Lit::Bool(bool) => Ok(bool.value),
+        _ => Err(syn::Error::new_spanned(lit, "expected `true` or `false`")),
+    }
 }
@@ -0,0 +1,24 @@
+use macros::BoiTyp;
+
+#[derive(BoiTyp)]
+enum Typ<const CHANNELS: usize> {
+    #[boi(code = 0b0, code_len = 1, size = 4)]
+    Pixel,
+
+    #[boi(code = 0b01, code_len = 2, size = 6)]
+    Repeating,
+
+    #[boi(code = 0b10, code_len = 2, size = 8)]
+    Offset,
+
+    #[boi(code = 0b10, code_len = 2, size = 4)]
+    Color,
+
+    #[boi(code = 0b1110, code_len = 3, size = 2)]
+    Gray,
+
+    #[boi(code = 0b1, code_len = 0, size = 2)]
+    Blob,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use macros::BoiTyp;
+
+#[derive(BoiTyp)]
+enum Typ {
+    #[boi(code = 0b0, code_len = 1, size = 4)]
+    Pixel,
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+use macros::BoiTyp;
+
+#[derive(BoiTyp)]
+enum Typ<const CHANNELS: usize> {
+    #[boi(code = 0b0, code_len = 1, size = 4, colour = 3)]
+    Pixel,
+
+    #[boi(code = 0b10, code_len = 2, code_len = 2, size = 6)]
+    Repeating,
+
+    #[boi(code = 0b11, code_len = 2)]
+    Offset,
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+use macros::BoiTyp;
+
+#[derive(BoiTyp)]
+enum Typ<const CHANNELS: usize> {
+    #[boi]
+    Pixel,
+
+    #[boi = 0b10]
+    Repeating,
+
+    #[boi(0b110, code(3))]
+    Offset,
+
+    #[boi(code = 0b111, code_len = 3, size = 8)]
+    #[boi(code = 0b111, code_len = 3, size = 8)]
+    Color,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use macros::BoiTyp;
+
+#[derive(BoiTyp)]
+enum Typ<const CHANNELS: usize> {
+    #[boi(code = 0b0, code_len = 1, size = 4)]
+    Pixel,
+
+    Offset,
+}
+
+fn main() {}
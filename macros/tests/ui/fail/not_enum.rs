@@ -0,0 +1,6 @@
+use macros::BoiTyp;
+
+#[derive(BoiTyp)]
+struct Typ<const CHANNELS: usize>;
+
+fn main() {}
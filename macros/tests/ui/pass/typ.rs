@@ -0,0 +1,27 @@
+use macros::BoiTyp;
+
+#[derive(BoiTyp)]
+enum Typ<const CHANNELS: usize> {
+    #[boi(code = 0b0, code_len = 1, size = 4, uses_channels = true)]
+    Pixel,
+
+    #[boi(code = 0b10, code_len = 2, size = 6, uses_channels = false)]
+    Repeating,
+
+    #[boi(code = 0b110, code_len = 3, size = 8)]
+    Offset,
+}
+
+fn main() {
+    assert_eq!(Typ::<3>::block_len(0b0), 12);
+    assert_eq!(Typ::<3>::block_len(0b10), 6);
+    assert_eq!(Typ::<3>::fields(0b110), (8, 1));
+    assert_eq!(Typ::<3>::Repeating.code(), 0b10);
+    assert_eq!(Typ::<3>::MAX_CODE_LEN, 3);
+    assert_eq!(Typ::<3>::KRAFT_SLACK, 1);
+    assert_eq!(Typ::<3>::CODE_LOOKUP[0b101], (0b10, 2));
+    assert_eq!(Typ::<3>::CODE_LOOKUP[0b111], (0, 0));
+    assert!(Typ::<3>::from_code(0b110, 3).is_some());
+    assert!(matches!(Typ::<3>::try_from(0b10), Ok(Typ::Repeating)));
+    assert_eq!(Typ::<3>::try_from(0b111).err(), Some(0b111));
+}
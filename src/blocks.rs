@@ -1,7 +1,12 @@
 use crate::img;
-use crate::{U8_BITS, USIZE_BITS};
+use crate::squares;
+use crate::{Error, U8_BITS};
 use macros::BoiTyp;
 
+/// The types of blocks. Their codes, the sizes of their payloads and the decoding of the codes are
+/// derived from the `boi` attributes, editing them is enough to change the layout of the blocks.
+/// The position of a variant is the symbol of its type for the entropy coding, see
+/// [`crate::entropy`].
 #[derive(BoiTyp)]
 pub enum Typ<const CHANNELS: usize> {
     #[boi(code = 0b110, code_len = 3, size = 4, uses_channels = true)]
@@ -11,10 +16,22 @@ pub enum Typ<const CHANNELS: usize> {
     #[boi(code = 0b1110, code_len = 4, size = 6, uses_channels = true)]
     Medium,
 
-    #[boi(code = 0b1111, code_len = 4, size = 9, uses_channels = true)]
+    #[boi(code = 0b11110, code_len = 5, size = 9, uses_channels = true)]
     //#[boi(code = 0b101, code_len = 3, size = 9, uses_channels = true)]
     Long,
 
+    /// Only needed by images with 16 bits samples, the deltas of 8 bits samples fit in a `Long`.
+    #[boi(code = 0b111110, code_len = 6, size = 16, uses_channels = true)]
+    Wide,
+
+    /// Followed by a pixel block with the value of every pixel of the blob.
+    #[boi(code = 0b1111110, code_len = 7, size = 24)]
+    Blob,
+
+    /// Followed by a pixel block with the value of every pixel of the region and by its mask.
+    #[boi(code = 0b1111111, code_len = 7, size = 24)]
+    Region,
+
     #[boi(code = 0b100, code_len = 3, size = 6)]
     Repeating,
 
@@ -30,38 +47,35 @@ pub enum Typ<const CHANNELS: usize> {
 
 #[derive(Debug)]
 pub struct Block {
-    /// Value of the byte
-    pub value: usize,
+    /// Value of the block. It is wider than a `usize` because a `Wide` block of 4 channels does not
+    /// fit in 64 bits once its code is added.
+    pub value: u128,
     /// The number of bits to represent the value
     pub bit_count: usize,
 }
 
 impl Block {
     #[inline]
-    pub fn new_with_code(
-        bit_count: usize,
-        mut value: usize,
-        code_len: usize,
-        mut code: usize,
-    ) -> Self {
-        code <<= bit_count;
-        value &= 2usize.pow(bit_count as u32) - 1;
-        value |= code;
+    pub fn new_with_code(bit_count: usize, value: usize, code_len: usize, code: usize) -> Self {
+        let value = value as u128 & ((1 << bit_count) - 1);
         Self {
-            value,
+            value: value | ((code as u128) << bit_count),
             bit_count: bit_count + code_len,
         }
     }
 
     #[inline]
     pub fn new(bit_count: usize, value: usize) -> Self {
-        Self { bit_count, value }
+        Self {
+            bit_count,
+            value: value as u128,
+        }
     }
 
     /// Turn the block into bytes with proper alignment based on the offset of the buffer. It also
     /// returns the new buffer offset. Return `(bytes, bytes_count, new_offset)`.
     #[inline]
-    pub fn into_bytes(self, buffer_offset: usize) -> ([u8; 8], usize, usize) {
+    pub fn into_bytes(self, buffer_offset: usize) -> ([u8; 16], usize, usize) {
         let total_offset = self.bit_count + buffer_offset;
         let new_offset = total_offset % U8_BITS;
         let mut bytes_count = total_offset / U8_BITS;
@@ -69,30 +83,11 @@ impl Block {
             bytes_count += 1;
         }
         (
-            (self.value << (USIZE_BITS - total_offset)).to_be_bytes(),
+            (self.value << (u128::BITS as usize - total_offset)).to_be_bytes(),
             bytes_count,
             new_offset,
         )
     }
-
-    #[inline]
-    pub fn block_len<const CHANNELS: usize>(code: usize) -> usize {
-        if code == 15 {
-            Repeating::BITS_COUNT
-        } else if code == 14 {
-            Pixel::<CHANNELS>::LONG_BITS as usize * CHANNELS
-        } else {
-            [
-                Pixel::<CHANNELS>::SHORT_BITS * CHANNELS,           // 0b0000
-                Offset::BITS_COUNT,                                 // 0b0001
-                0,                                                  // 0b0010
-                0,                                                  // 0b0011
-                Pixel::<CHANNELS>::MEDIUM_BITS as usize * CHANNELS, // 0b0101
-                Gray::<CHANNELS>::BITS_COUNT,                       // 0b0110
-                Color::BITS_COUNT,                                  // 0b0111
-            ][code]
-        }
-    }
 }
 
 /// A count of repeating pixels with the exact same value (all the channels). The value of the
@@ -106,6 +101,8 @@ impl Repeating {
     const CODE_LEN: usize = Typ::<0>::REPEATING_CODE_LEN;
     const CODE: usize = Typ::<0>::REPEATING_CODE;
     pub const MAX: usize = 2usize.pow(Self::BITS_COUNT as u32);
+    /// Number of bits of the block, including its code.
+    pub const TOTAL_BITS: usize = Self::BITS_COUNT + Self::CODE_LEN;
 
     // bits : 8
 
@@ -136,10 +133,28 @@ impl<const CHANNELS: usize> Gray<CHANNELS> {
         )
     }
 
+    #[inline]
+    pub fn decode(value: usize) -> img::Pixel<CHANNELS> {
+        let gray = Pixel::<CHANNELS>::extend_sign(value, Self::BITS_COUNT) as i16;
+        let mut pixel = [0; CHANNELS];
+        pixel
+            .iter_mut()
+            .take(img::Pixel::<CHANNELS>::COLOR_CHANNELS)
+            .for_each(|channel| *channel = gray);
+        img::Pixel(pixel)
+    }
+
+    /// A pixel is gray when the color channels have the same small value and the alpha channel
+    /// (if any) does not change. Gray images only have one color channel, any small value of that
+    /// channel is gray.
     #[inline]
     pub fn is_gray(pixel: &img::Pixel<CHANNELS>) -> bool {
-        if pixel.0[0] == pixel.0[1] && pixel.0[1] == pixel.0[2] {
-            pixel.0[0] <= Self::MAX && pixel.0[1] >= Self::MIN
+        let (colors, alpha) = pixel.0.split_at(img::Pixel::<CHANNELS>::COLOR_CHANNELS);
+        if alpha.iter().any(|alpha| *alpha != 0) {
+            return false;
+        }
+        if colors.iter().all(|color| *color == colors[0]) {
+            colors[0] <= Self::MAX && colors[0] >= Self::MIN
         } else {
             false
         }
@@ -159,11 +174,138 @@ impl Offset {
 
     // const BITS_COUNT: usize = 8;
     pub const MASK: usize = 0b111111;
+    /// The number of pixels of the smallest cache of the offsets, the index of its pixels fills
+    /// the payload of the block.
     pub const MAX: usize = 2usize.pow(Self::BITS_COUNT as u32);
+    /// The number of pixels of the largest cache of the offsets.
+    pub const MAX_CACHE: usize = 1 << 12;
+    /// The maximum number of pixels of a set of the cache of the offsets.
+    pub const MAX_WAYS: usize = 8;
+    /// Number of bits of the block with the smallest cache, including its code.
+    pub const TOTAL_BITS: usize = Self::BITS_COUNT + Self::CODE_LEN;
+
+    /// Encode the index of a pixel in a cache of `len` pixels. The bits of the index that do not
+    /// fit in the payload follow it, see [`Offset::extra_bits`].
+    #[inline]
+    pub fn encode(value: usize, len: usize) -> Block {
+        Block::new_with_code(len.ilog2() as usize, value, Self::CODE_LEN, Self::CODE)
+    }
 
+    /// Return the number of bits of the index following the payload for a cache of `len` pixels.
     #[inline]
-    pub fn encode(value: usize) -> Block {
-        Block::new_with_code(Self::BITS_COUNT, value, Self::CODE_LEN, Self::CODE)
+    pub fn extra_bits(len: usize) -> usize {
+        len.ilog2() as usize - Self::BITS_COUNT
+    }
+}
+
+/// A rectangle of pixels with the same value, see [`squares::Blob`]. The rectangle starts at the
+/// pixel where the block appears, only its size is stored. Like for [`Repeating`], the width and
+/// the height are shifted by 1.
+pub struct Blob;
+
+impl Blob {
+    const BITS_COUNT: usize = Typ::<0>::BLOB_SIZE;
+    const CODE_LEN: usize = Typ::<0>::BLOB_CODE_LEN;
+    const CODE: usize = Typ::<0>::BLOB_CODE;
+
+    const SIDE_BITS: usize = Self::BITS_COUNT / 2;
+    const SIDE_MASK: usize = Self::MAX - 1;
+    /// The maximum width and height of a blob.
+    pub const MAX: usize = 2usize.pow(Self::SIDE_BITS as u32);
+
+    #[inline]
+    pub fn encode(width: usize, height: usize) -> Block {
+        Block::new_with_code(
+            Self::BITS_COUNT,
+            Self::encode_size(width, height),
+            Self::CODE_LEN,
+            Self::CODE,
+        )
+    }
+
+    #[inline]
+    fn encode_size(width: usize, height: usize) -> usize {
+        ((width - 1) << Self::SIDE_BITS) | (height - 1)
+    }
+
+    /// Return `(width, height)`.
+    #[inline]
+    pub fn decode(value: usize) -> (usize, usize) {
+        (
+            ((value >> Self::SIDE_BITS) & Self::SIDE_MASK) + 1,
+            (value & Self::SIDE_MASK) + 1,
+        )
+    }
+}
+
+/// A set of pixels with the same value and any shape, see [`squares::Region`]. The size of the
+/// bounding box of the region is stored like for a [`Blob`], followed by the pixel block with the
+/// value and by the mask of the region, row by row:
+/// - every row but the first starts with a bit set when the row is the same as the previous one,
+/// - otherwise the row is made of the length of the runs outside and inside of the region,
+///   alternatively, until the width of the bounding box is reached. The first run is outside of
+///   the region and can be empty.
+///
+/// The region starts at the pixel where the block appears, which is the end of the first run.
+pub struct Region<const CHANNELS: usize>;
+
+impl<const CHANNELS: usize> Region<CHANNELS> {
+    const BITS_COUNT: usize = Typ::<0>::REGION_SIZE;
+    const CODE_LEN: usize = Typ::<0>::REGION_CODE_LEN;
+    const CODE: usize = Typ::<0>::REGION_CODE;
+
+    /// Return the number of bits of a run in a region `width` pixels wide.
+    #[inline]
+    pub fn run_bits(width: usize) -> usize {
+        (usize::BITS - width.leading_zeros()) as usize
+    }
+
+    /// Return the blocks of the region: the size, the value and the mask.
+    pub fn encode(region: &squares::Region<CHANNELS>) -> Vec<Block> {
+        let (left, right) = region.bounds();
+        let bits = Self::run_bits(right - left);
+        let mut blocks = vec![
+            Block::new_with_code(
+                Self::BITS_COUNT,
+                Blob::encode_size(right - left, region.height()),
+                Self::CODE_LEN,
+                Self::CODE,
+            ),
+            Pixel::encode(&region.fill),
+        ];
+        for (idx, spans) in region.rows.iter().enumerate() {
+            if idx > 0 {
+                let is_same = *spans == region.rows[idx - 1];
+                blocks.push(Block::new(1, is_same as usize));
+                if is_same {
+                    continue;
+                }
+            }
+            let mut position = left;
+            for (start, end) in spans.iter() {
+                blocks.push(Block::new(bits, start - position));
+                blocks.push(Block::new(bits, end - start));
+                position = *end;
+            }
+            if position < right {
+                blocks.push(Block::new(bits, right - position));
+            }
+        }
+        blocks
+    }
+
+    /// Return the number of bits needed to encode the region.
+    pub fn bit_count(region: &squares::Region<CHANNELS>) -> usize {
+        Self::encode(region)
+            .iter()
+            .map(|block| block.bit_count)
+            .sum()
+    }
+
+    /// Return the width and the height of the bounding box of the region.
+    #[inline]
+    pub fn decode(value: usize) -> (usize, usize) {
+        Blob::decode(value)
     }
 }
 
@@ -182,54 +324,91 @@ impl<const CHANNELS: usize> Pixel<CHANNELS> {
     const LONG_CODE_LEN: usize = Typ::<CHANNELS>::LONG_CODE_LEN;
     const LONG_CODE: usize = Typ::<CHANNELS>::LONG_CODE;
 
+    const WIDE_BITS: usize = Typ::<CHANNELS>::WIDE_SIZE;
+    const WIDE_CODE_LEN: usize = Typ::<CHANNELS>::WIDE_CODE_LEN;
+    const WIDE_CODE: usize = Typ::<CHANNELS>::WIDE_CODE;
+
     const SHORT_MIN: i16 = -2i16.pow(Self::SHORT_BITS as u32) / 2;
     const SHORT_MAX: i16 = (2i16.pow(Self::SHORT_BITS as u32) / 2) - 1;
     const MEDIUM_MIN: i16 = -2i16.pow(Self::MEDIUM_BITS as u32) / 2;
     const MEDIUM_MAX: i16 = (2i16.pow(Self::MEDIUM_BITS as u32) / 2) - 1;
+    const LONG_MIN: i16 = -2i16.pow(Self::LONG_BITS as u32) / 2;
+    const LONG_MAX: i16 = (2i16.pow(Self::LONG_BITS as u32) / 2) - 1;
 
     #[inline]
     pub fn encode(pixel: &img::Pixel<CHANNELS>) -> Block {
+        Self::encode_with_typ(pixel).1
+    }
+
+    /// Encode the pixel in the smallest block and return the type of that block.
+    #[inline]
+    pub fn encode_with_typ(pixel: &img::Pixel<CHANNELS>) -> (Typ<CHANNELS>, Block) {
         let min = *pixel.0.iter().min().unwrap();
         let max = *pixel.0.iter().max().unwrap();
-        let channel = 3;
-        let (channel_size, code, code_len) = if min >= Self::SHORT_MIN && max <= Self::SHORT_MAX {
-            (Self::SHORT_BITS, Self::SHORT_CODE, Self::SHORT_CODE_LEN)
-        } else if min >= Self::MEDIUM_MIN && max <= Self::MEDIUM_MAX {
-            (Self::MEDIUM_BITS, Self::MEDIUM_CODE, Self::MEDIUM_CODE_LEN)
-        } else {
-            (Self::LONG_BITS, Self::LONG_CODE, Self::LONG_CODE_LEN)
-        };
+        let (typ, channel_size, code, code_len) =
+            if min >= Self::SHORT_MIN && max <= Self::SHORT_MAX {
+                (
+                    Typ::Short,
+                    Self::SHORT_BITS,
+                    Self::SHORT_CODE,
+                    Self::SHORT_CODE_LEN,
+                )
+            } else if min >= Self::MEDIUM_MIN && max <= Self::MEDIUM_MAX {
+                (
+                    Typ::Medium,
+                    Self::MEDIUM_BITS,
+                    Self::MEDIUM_CODE,
+                    Self::MEDIUM_CODE_LEN,
+                )
+            } else if min >= Self::LONG_MIN && max <= Self::LONG_MAX {
+                (
+                    Typ::Long,
+                    Self::LONG_BITS,
+                    Self::LONG_CODE,
+                    Self::LONG_CODE_LEN,
+                )
+            } else {
+                (
+                    Typ::Wide,
+                    Self::WIDE_BITS,
+                    Self::WIDE_CODE,
+                    Self::WIDE_CODE_LEN,
+                )
+            };
         let value = Self::encode_channels(pixel, channel_size);
-        Block::new_with_code(channel_size * channel, value, code_len, code)
+        (
+            typ,
+            Block::new_with_code(channel_size * CHANNELS, value, code_len, code),
+        )
     }
 
     #[inline]
     fn encode_channels(pixel: &img::Pixel<CHANNELS>, channel_size: usize) -> usize {
         let mask = 2usize.pow(channel_size as u32) - 1;
-        let channel = 3; // TODO: Check for the alpha channel.
-        (0..channel).fold(0, |mut value, index| {
-            value = (value as usize) << channel_size;
-            value | ((pixel.0[index] as usize) & mask)
+        pixel.0.iter().fold(0, |mut value, channel| {
+            value <<= channel_size;
+            value | ((*channel as usize) & mask)
         })
     }
 
     #[inline]
-    pub fn decode(value: usize, code: usize) -> img::Pixel<CHANNELS> {
+    pub fn decode(value: usize, code: usize) -> Result<img::Pixel<CHANNELS>, Error> {
         let channel_size = match code {
             Self::SHORT_CODE => Self::SHORT_BITS,
             Self::MEDIUM_CODE => Self::MEDIUM_BITS,
             Self::LONG_CODE => Self::LONG_BITS,
-            _ => panic!("Code: {}", code),
+            Self::WIDE_CODE => Self::WIDE_BITS,
+            _ => return Err(Error::InvalidData("expected a pixel block")),
         };
         let mut pixel = [0; CHANNELS];
         let mask = 2usize.pow(channel_size as u32) - 1;
-        for idx in 0..CHANNELS {
-            pixel[idx] = Self::extend_sign(
-                (value >> (CHANNELS - idx - 1) * channel_size) & mask,
+        for (idx, channel) in pixel.iter_mut().enumerate() {
+            *channel = Self::extend_sign(
+                (value >> ((CHANNELS - idx - 1) * channel_size)) & mask,
                 channel_size,
             ) as i16;
         }
-        img::Pixel(pixel)
+        Ok(img::Pixel(pixel))
     }
 
     /// Resize a binary complement's 2 number from a size to another size (the size is the number of
@@ -254,6 +433,8 @@ impl Color {
     const CODE: usize = Typ::<0>::COLOR_CODE;
 
     pub const MAX: usize = 2usize.pow(Self::BITS_COUNT as u32);
+    /// Number of bits of the block, including its code.
+    pub const TOTAL_BITS: usize = Self::BITS_COUNT + Self::CODE_LEN;
 
     #[inline]
     pub fn encode(value: usize) -> Block {
@@ -299,3 +480,36 @@ mod tests {
     #[test]
     fn encode_offset() {}
 }*/
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn codes() {
+        type Typ = super::Typ<3>;
+        assert_eq!(Typ::MAX_CODE_LEN, 7);
+        // The codes are complete, a new type of block needs a longer code.
+        assert_eq!(Typ::KRAFT_SLACK, 0);
+        assert_eq!(Typ::CODES[0], 0b110);
+        assert_eq!(Typ::Repeating.code(), 0b100);
+        assert_eq!(Typ::code_len(0b100), 3);
+        assert_eq!(Typ::block_len(0b110), 12);
+        assert_eq!(Typ::block_len(0b100), 6);
+        assert_eq!(Typ::fields(0b111110), (16, 3));
+        assert_eq!(Typ::fields(0b00), (8, 1));
+        assert_eq!(Typ::code_len(0b111), 0);
+        assert!(Typ::from_code(0b11, 2).is_none());
+
+        // Every value of the lookup table starts with the code it maps to.
+        for (symbol, code) in Typ::CODES.iter().enumerate() {
+            let code_len = Typ::code_len(*code);
+            assert_eq!(
+                Typ::from_code(*code, code_len).map(|typ| typ.code()),
+                Some(*code)
+            );
+            let shift = Typ::MAX_CODE_LEN - code_len;
+            for bits in (code << shift)..((code + 1) << shift) {
+                assert_eq!(Typ::CODE_LOOKUP[bits], (*code, code_len), "{symbol}");
+            }
+        }
+    }
+}
@@ -1,11 +1,19 @@
 use crate::blocks;
+use crate::entropy;
 use crate::img;
+use crate::palette;
+use crate::rans;
+use crate::Error;
 use crate::U8_BITS;
 
 /// A buffer that handle that without proper alignment. It's just a lots of bit manipulation.
 pub struct Buffer {
     pub bytes: Vec<u8>,
     offset: usize,
+    /// The Huffman codes of the blocks, see [`Buffer::push_block`].
+    tables: Option<entropy::Tables>,
+    /// The rANS encoder of everything following the header, see [`Buffer::push_checksum`].
+    rans: Option<rans::RansEncoder>,
 }
 
 impl Buffer {
@@ -13,6 +21,8 @@ impl Buffer {
         Self {
             bytes: Vec::with_capacity(capacity),
             offset: 0,
+            tables: None,
+            rans: None,
         }
     }
 
@@ -20,8 +30,82 @@ impl Buffer {
         self.bytes.len()
     }
 
-    /// Push an unaligned data payload to an unaligned buffer.
+    /// Push the header of an image. The palette is followed by the Huffman codes of the blocks, if
+    /// any, and by the checksum of the header. The following blocks are coded with these codes, or
+    /// with rANS.
+    pub fn push_header<const CHANNELS: usize>(&mut self, header: img::Header<CHANNELS>) {
+        for byte in header.info.to_bytes() {
+            self.push(blocks::Block::new(U8_BITS, byte as usize));
+        }
+        header.metadata.encode(self);
+        self.push(blocks::Block::new(
+            palette::Palette::<CHANNELS>::LEN_BITS,
+            header.encoded_palette.len(),
+        ));
+        header
+            .encoded_palette
+            .into_iter()
+            .for_each(|block| self.push(block));
+        if let Some(tables) = &header.tables {
+            tables.write(self);
+        }
+        self.push_checksum(header.checksum);
+        self.tables = header.tables;
+        if header.info.entropy == entropy::Entropy::Rans {
+            self.rans = Some(rans::RansEncoder::new());
+        }
+    }
+
+    /// Push a checksum, the checksum of the pixels follows the last block of the image. The bytes
+    /// of rANS are written before it, the checksum itself is not coded.
+    pub fn push_checksum(&mut self, checksum: u32) {
+        if let Some(rans) = self.rans.take() {
+            for byte in rans.finish() {
+                self.push(blocks::Block::new(U8_BITS, byte as usize));
+            }
+        }
+        self.push(blocks::Block::new(u32::BITS as usize, checksum as usize));
+    }
+
+    /// Write the bytes that are complete to `writer` and remove them from the buffer. The last
+    /// byte is kept if some of its bits are still free.
+    pub fn flush<W: std::io::Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        let complete = if self.offset == 0 {
+            self.bytes.len()
+        } else {
+            self.bytes.len() - 1
+        };
+        writer.write_all(&self.bytes[..complete])?;
+        self.bytes.drain(..complete);
+        Ok(())
+    }
+
+    /// Push a block with the prefix code `code`, with the Huffman codes of the header or rANS if
+    /// any.
+    #[inline]
+    pub fn push_block<const CHANNELS: usize>(&mut self, code: usize, block: blocks::Block) {
+        if let Some(rans) = &mut self.rans {
+            rans.push_block::<CHANNELS>(code, block);
+            return;
+        }
+        match self.tables.take() {
+            Some(tables) => {
+                tables.encode::<CHANNELS>(code, block, self);
+                self.tables = Some(tables);
+            }
+            None => self.push(block),
+        }
+    }
+
+    /// Push an unaligned data payload to an unaligned buffer, through rANS if it is active.
     pub fn push(&mut self, byte: blocks::Block) {
+        match &mut self.rans {
+            Some(rans) => rans.push(byte),
+            None => self.push_raw(byte),
+        }
+    }
+
+    fn push_raw(&mut self, byte: blocks::Block) {
         let (bytes, count, new_offset) = byte.into_bytes(self.offset);
         let bytes = &bytes[..count];
         let bytes_count = self.bytes.len();
@@ -39,80 +123,166 @@ impl Buffer {
 
 impl<const CHANNELS: usize> From<img::Header<CHANNELS>> for Buffer {
     fn from(header: img::Header<CHANNELS>) -> Self {
-        let mut buffer = Buffer::new((header.width * header.height) as usize);
-        let alpha_code = if CHANNELS == 3 { 0 } else { 1 };
-        buffer.push(blocks::Block::new(1, alpha_code));
-        buffer.push(blocks::Block::new(
-            u32::BITS as usize,
-            header.width as usize,
-        ));
-        buffer.push(blocks::Block::new(
-            u32::BITS as usize,
-            header.height as usize,
-        ));
-        header
-            .encoded_palette
-            .into_iter()
-            .for_each(|block| buffer.push(block));
+        let mut buffer = Buffer::new(header.info.pixels_count());
+        buffer.push_header(header);
         buffer
     }
 }
 
-pub struct BufferDecoder {
-    pub bytes: Vec<u8>,
+/// Number of bytes read at once by the [`BufferDecoder`].
+const CHUNK_SIZE: usize = 4096;
+
+/// Read unaligned blocks from any `std::io::Read`. The bytes are read by chunks of
+/// [`CHUNK_SIZE`], the reader can therefore be consumed a bit after the end of the image.
+pub struct BufferDecoder<R: std::io::Read> {
+    reader: R,
+    bytes: Vec<u8>,
     offset: usize,
     index: usize,
+    /// The Huffman codes of the blocks read from the header, if any.
+    pub tables: Option<entropy::Tables>,
+    /// The rANS decoder of everything following the header, if any.
+    pub rans: Option<rans::RansDecoder>,
 }
 
-impl BufferDecoder {
-    pub fn next_block<const CHANNELS: usize>(&mut self) -> Option<(usize, usize)> {
-        let code = self.next_code()?;
-        let value = self.next_n_bits(blocks::Block::block_len::<CHANNELS>(code))?;
-        Some((code, value))
+impl<R: std::io::Read> BufferDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            bytes: Vec::new(),
+            offset: 0,
+            index: 0,
+            tables: None,
+            rans: None,
+        }
     }
 
-    fn next_code(&mut self) -> Option<usize> {
-        let start = self.next_n_bits(1)?;
-        if start == 0 {
-            self.next_n_bits(1)
-        } else {
-            Some(0b100 | self.next_n_bits(2)?)
+    /// Read the next block, with the Huffman codes or rANS if any, and return its prefix code and
+    /// its payload.
+    #[inline]
+    pub fn next_block<const CHANNELS: usize>(&mut self) -> Result<(usize, usize), Error> {
+        if let Some(mut rans) = self.rans.take() {
+            let block = rans.next_block::<CHANNELS, R>(self);
+            self.rans = Some(rans);
+            return block;
+        }
+        match self.tables.take() {
+            Some(tables) => {
+                let block = tables.decode::<CHANNELS, R>(self);
+                self.tables = Some(tables);
+                block
+            }
+            None => self.next_raw_block::<CHANNELS>(),
+        }
+    }
+
+    /// Read the next block written as is and return its prefix code and its payload.
+    pub fn next_raw_block<const CHANNELS: usize>(&mut self) -> Result<(usize, usize), Error> {
+        let code = self.next_code::<CHANNELS>()?;
+        let value = self.next_n_bits(blocks::Typ::<CHANNELS>::block_len(code))?;
+        Ok((code, value))
+    }
+
+    /// Read the prefix code of the next block, see [`blocks::Typ`].
+    fn next_code<const CHANNELS: usize>(&mut self) -> Result<usize, Error> {
+        if self.rans.is_some() {
+            // rANS cannot peek, the code is read bit by bit instead.
+            let mut code = 0;
+            for code_len in 1..=blocks::Typ::<CHANNELS>::MAX_CODE_LEN {
+                code = (code << 1) | self.next_n_bits(1)?;
+                if blocks::Typ::<CHANNELS>::from_code(code, code_len).is_some() {
+                    return Ok(code);
+                }
+            }
+            return Err(Error::InvalidData("unknown block code"));
+        }
+        let bits = self.peek_n_bits(blocks::Typ::<CHANNELS>::MAX_CODE_LEN)?;
+        match blocks::Typ::<CHANNELS>::CODE_LOOKUP[bits] {
+            (_, 0) => Err(Error::InvalidData("unknown block code")),
+            (code, code_len) => {
+                self.read_bits(code_len)?;
+                Ok(code)
+            }
+        }
+    }
+
+    /// Read the next `n` bits, through rANS if it is active.
+    pub fn next_n_bits(&mut self, n: usize) -> Result<usize, Error> {
+        match self.rans.take() {
+            Some(mut rans) => {
+                let bits = rans.next_bits(n, self);
+                self.rans = Some(rans);
+                bits
+            }
+            None => self.read_bits(n),
         }
     }
 
-    pub fn next_n_bits(&mut self, n: usize) -> Option<usize> {
+    /// Read the checksum following the last block of the image, which is never coded. The last
+    /// chunk of rANS, if any, must have been read entirely.
+    pub fn next_checksum(&mut self) -> Result<u32, Error> {
+        if let Some(rans) = self.rans.take() {
+            rans.finish()?;
+        }
+        Ok(self.read_bits(u32::BITS as usize)? as u32)
+    }
+
+    /// Read the next `n` bits as they are written.
+    pub fn read_bits(&mut self, n: usize) -> Result<usize, Error> {
         let len = n + self.offset;
         let mut bytes_count = len / U8_BITS;
         let new_offset = len % U8_BITS;
         if new_offset > 0 {
             bytes_count += 1;
         }
-        if self.index + bytes_count > self.bytes.len() {
-            return None;
-        }
-        let mut out: usize = 0;
+        self.fill(bytes_count)?;
+        // A `Wide` block of 4 channels with an offset does not fit in an `usize`.
+        let mut out: u128 = 0;
         let bytes = &self.bytes[self.index..self.index + bytes_count];
         for byte in bytes.iter() {
-            out = (out << U8_BITS) | *byte as usize;
+            out = (out << U8_BITS) | *byte as u128;
         }
-        out = out >> (bytes_count * U8_BITS - len);
-        out &= 2usize.pow(n as u32) - 1;
+        out >>= bytes_count * U8_BITS - len;
+        out &= (1 << n) - 1;
         self.index += bytes_count - 1;
         self.offset = new_offset;
         if self.offset == 0 {
             self.index += 1;
         }
-        Some(out)
+        Ok(out as usize)
     }
-}
 
-impl From<Vec<u8>> for BufferDecoder {
-    fn from(raw: Vec<u8>) -> Self {
-        Self {
-            bytes: raw,
-            offset: 0,
-            index: 0,
+    /// Return the next `n` bits without consuming them.
+    pub fn peek_n_bits(&mut self, n: usize) -> Result<usize, Error> {
+        if n == 0 {
+            return Ok(0);
+        }
+        // Once the bytes are available, reading them does not move the bytes of the buffer.
+        self.fill((n + self.offset).div_ceil(U8_BITS))?;
+        let (index, offset) = (self.index, self.offset);
+        let bits = self.read_bits(n);
+        (self.index, self.offset) = (index, offset);
+        bits
+    }
+
+    /// Make sure that at least `count` bytes are available after the current index.
+    #[inline]
+    fn fill(&mut self, count: usize) -> Result<(), Error> {
+        if self.index + count <= self.bytes.len() {
+            return Ok(());
+        }
+        self.bytes.drain(..self.index);
+        self.index = 0;
+        let mut chunk = [0; CHUNK_SIZE];
+        while self.bytes.len() < count {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => return Err(Error::Truncated),
+                Ok(read) => self.bytes.extend_from_slice(&chunk[..read]),
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error.into()),
+            }
         }
+        Ok(())
     }
 }
 
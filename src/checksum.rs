@@ -0,0 +1,59 @@
+/// The CRC-32 (ISO-HDLC) used to detect corrupted images, the same one as png and zlib use.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    const POLYNOMIAL: u32 = 0xEDB8_8320;
+    const TABLE: [u32; 256] = Self::table();
+
+    pub fn new() -> Self {
+        Self(!0)
+    }
+
+    /// Compute the crc of every byte value at compile time.
+    const fn table() -> [u32; 256] {
+        let mut table = [0; 256];
+        let mut idx = 0;
+        while idx < table.len() {
+            let mut crc = idx as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ Self::POLYNOMIAL
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[idx] = crc;
+            idx += 1;
+        }
+        table
+    }
+
+    /// Add `bytes` to the checksum.
+    #[inline]
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = Self::TABLE[((self.0 ^ *byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    /// Return the checksum of all the bytes added so far.
+    pub fn value(&self) -> u32 {
+        !self.0
+    }
+
+    /// Return the checksum of `bytes`.
+    pub fn compute(bytes: &[u8]) -> u32 {
+        let mut crc = Self::new();
+        crc.update(bytes);
+        crc.value()
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
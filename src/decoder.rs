@@ -1,68 +1,427 @@
 use crate::blocks;
 use crate::buffer;
+use crate::checksum;
+use crate::entropy;
 use crate::img;
+use crate::metadata;
+use crate::offsets;
+use crate::palette;
+use crate::predictor;
+use crate::rans;
+use crate::squares;
+use crate::Error;
+use std::rc::Rc;
 
-pub fn decode(raw: Vec<u8>) -> (Vec<u8>, u32, u32) {
-    let is_alpha = (raw[0] & 0b10000000) == 1;
-    if is_alpha {
-        Decoder::<4>::decode(raw)
-    } else {
-        Decoder::<3>::decode(raw)
+/// A decoded image. The pixels are stored row by row with the channels interleaved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedImage {
+    pub info: img::ImageInfo,
+    pub metadata: metadata::Metadata,
+    pub pixels: Vec<u8>,
+}
+
+/// The most bytes allocated for the pixels before they are decoded. The dimensions come from an
+/// untrusted header, the pixels grow with the rows actually decoded beyond it.
+const MAX_PREALLOCATION: usize = 1 << 26;
+
+/// Decode a boi image.
+pub fn decode(raw: &[u8]) -> Result<DecodedImage, Error> {
+    let mut decoder = StreamDecoder::new(raw)?;
+    let info = *decoder.info();
+    let row_len = info.row_len();
+    let mut pixels = Vec::with_capacity(info.raw_len()?.min(MAX_PREALLOCATION));
+    for _ in 0..info.height {
+        let start = pixels.len();
+        pixels.resize(start + row_len, 0);
+        decoder.read_row(&mut pixels[start..])?;
+    }
+    Ok(DecodedImage {
+        info,
+        metadata: decoder.metadata,
+        pixels,
+    })
+}
+
+/// A decoder that reads the image from any `std::io::Read` and returns it row by row. Only a row
+/// and the offsets are kept in memory.
+pub struct StreamDecoder<R: std::io::Read> {
+    info: img::ImageInfo,
+    metadata: metadata::Metadata,
+    buffer: buffer::BufferDecoder<R>,
+    state: Box<dyn RowDecoder<R>>,
+    checksum: checksum::Crc32,
+    rows: u32,
+}
+
+impl<R: std::io::Read> StreamDecoder<R> {
+    /// Create the decoder and read the header of the image.
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mut buffer = buffer::BufferDecoder::new(reader);
+        let (info, version) = img::ImageInfo::decode(&mut buffer)?;
+        let (state, metadata) = match info.bit_depth {
+            img::BitDepth::Eight => Self::init::<u8>(info, version, &mut buffer)?,
+            img::BitDepth::Sixteen => Self::init::<u16>(info, version, &mut buffer)?,
+        };
+        Ok(Self {
+            info,
+            metadata,
+            buffer,
+            state,
+            checksum: checksum::Crc32::new(),
+            rows: 0,
+        })
+    }
+
+    fn init<S: img::Sample>(
+        info: img::ImageInfo,
+        version: u8,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<(Box<dyn RowDecoder<R>>, metadata::Metadata), Error> {
+        match info.channels {
+            1 => Self::init_channels::<S, 1>(info, version, buffer),
+            2 => Self::init_channels::<S, 2>(info, version, buffer),
+            3 => Self::init_channels::<S, 3>(info, version, buffer),
+            _ => Self::init_channels::<S, 4>(info, version, buffer),
+        }
+    }
+
+    fn init_channels<S: img::Sample, const CHANNELS: usize>(
+        info: img::ImageInfo,
+        version: u8,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<(Box<dyn RowDecoder<R>>, metadata::Metadata), Error> {
+        let header = img::Header::<CHANNELS>::decode(info, version, buffer)?;
+        // The blocks following the header are read with its Huffman codes or with rANS, if any.
+        buffer.tables = header.tables;
+        if info.entropy == entropy::Entropy::Rans {
+            buffer.rans = Some(rans::RansDecoder::new());
+        }
+        let state = State::<S, CHANNELS>::new(info, header.palette);
+        Ok((Box::new(state), header.metadata))
+    }
+
+    /// The description of the image read from the header.
+    pub fn info(&self) -> &img::ImageInfo {
+        &self.info
+    }
+
+    /// The metadata read from the header.
+    pub fn metadata(&self) -> &metadata::Metadata {
+        &self.metadata
     }
+
+    /// Decode the next row of the image into `row`. `row` must contain exactly `width * channels`
+    /// samples.
+    pub fn read_row(&mut self, row: &mut [u8]) -> Result<(), Error> {
+        let expected = self.info.row_len();
+        if row.len() != expected {
+            return Err(Error::InvalidBufferLength {
+                expected,
+                actual: row.len(),
+            });
+        }
+        if self.rows == self.info.height {
+            return Err(Error::InvalidData("all the rows were already read"));
+        }
+        self.state.decode_row(&mut self.buffer, row)?;
+        self.checksum.update(row);
+        self.rows += 1;
+        if self.rows == self.info.height {
+            self.state.finish()?;
+            let expected = self.buffer.next_checksum()?;
+            let actual = self.checksum.value();
+            if expected != actual {
+                return Err(Error::ChecksumMismatch { expected, actual });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: std::io::Read> Iterator for StreamDecoder<R> {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rows == self.info.height {
+            return None;
+        }
+        let mut row = vec![0; self.info.row_len()];
+        match self.read_row(&mut row) {
+            Ok(()) => Some(Ok(row)),
+            Err(error) => {
+                // The stream cannot be decoded past an error.
+                self.rows = self.info.height;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Decode the rows of an image when the number of channels is only known at runtime.
+trait RowDecoder<R: std::io::Read> {
+    fn decode_row(
+        &mut self,
+        buffer: &mut buffer::BufferDecoder<R>,
+        row: &mut [u8],
+    ) -> Result<(), Error>;
+    fn finish(&self) -> Result<(), Error>;
+}
+
+/// The state of the decoder between two pixels. The pixels are decoded one by one in the order
+/// they appear in the image.
+pub struct State<S: img::Sample, const CHANNELS: usize> {
+    info: img::ImageInfo,
+    /// The palettes of the tiles of the current row of tiles, or the palette of the header when
+    /// the image has no tiles.
+    palettes: Vec<Vec<img::Pixel<CHANNELS>>>,
+    /// The size of the tiles, `usize::MAX` when the image has no tiles.
+    tile_size: usize,
+    offsets: offsets::Offsets<CHANNELS>,
+    /// The number of bits of the index of the offsets following the payload of their block.
+    offset_extra_bits: usize,
+    previous: img::Pixel<CHANNELS>,
+    /// The predictor of the current row, read from its tag when the predictor of the image is
+    /// adaptive.
+    row_predictor: predictor::Predictor,
+    neighbors: predictor::Neighbors<S, CHANNELS>,
+    repeating: usize,
+    /// The regions covering the current row or the following ones.
+    regions: Vec<squares::Region<CHANNELS>>,
+    /// The spans of the current row covered by a region, sorted by their start, and their value.
+    spans: Vec<(squares::Span, img::Pixel<CHANNELS>)>,
+    /// The first span of `spans` that may cover the next pixel.
+    span_idx: usize,
+    /// The index of the next pixel in the image.
+    index: usize,
 }
 
-pub struct Decoder<const CHANNELS: usize>;
-
-impl<const CHANNELS: usize> Decoder<CHANNELS> {
-    pub fn decode(raw: Vec<u8>) -> (Vec<u8>, u32, u32) {
-        let mut buffer = buffer::BufferDecoder::from(raw);
-        let header = img::Header::<CHANNELS>::from(&mut buffer);
-        let mut pixels: Vec<img::Pixel<CHANNELS>> =
-            Vec::with_capacity((header.width * header.height) as usize);
-        let mut offsets = [img::Pixel::<CHANNELS>::zeros(); blocks::Offset::MAX];
-        let mut previous = img::Pixel::<CHANNELS>::zeros();
-
-        // -1 -2
-        // -1 -(-2) = -1 + 2 = 1
-        // -1 - 1
-        while let Some((code, encoded_value)) = buffer.next_block::<CHANNELS>() {
-            match code {
-                0b00 | 0b101 | 0b110 => {
-                    let pixel = blocks::Pixel::<CHANNELS>::decode(encoded_value, code);
-                    offsets[pixel.hash()] = pixel;
-                    pixels.push(pixel);
-                    previous = pixel;
+impl<S: img::Sample, const CHANNELS: usize> State<S, CHANNELS> {
+    pub fn new(info: img::ImageInfo, palette: Vec<img::Pixel<CHANNELS>>) -> Self {
+        let tile_size = match info.tile_size {
+            0 => usize::MAX,
+            size => size as usize,
+        };
+        Self {
+            info,
+            palettes: vec![palette],
+            tile_size,
+            offsets: offsets::Offsets::new(info.offsets as usize, info.offset_ways as usize),
+            offset_extra_bits: blocks::Offset::extra_bits(info.offsets as usize),
+            previous: img::Pixel::zeros(),
+            row_predictor: info.predictor,
+            neighbors: predictor::Neighbors::new(info.width as usize),
+            repeating: 0,
+            regions: Vec::new(),
+            spans: Vec::new(),
+            span_idx: 0,
+            index: 0,
+        }
+    }
+
+    #[inline]
+    pub fn next<R: std::io::Read>(
+        &mut self,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<img::Pixel<CHANNELS>, Error> {
+        let width = self.info.width as usize;
+        let (x, y) = (self.index % width, self.index / width);
+        if x == 0 {
+            self.next_row(y);
+        }
+        let pixel = if let Some(fill) = self.covering_fill(x) {
+            fill
+        } else if self.repeating > 0 {
+            self.repeating -= 1;
+            self.previous
+        } else {
+            let (code, encoded_value) = buffer.next_block::<CHANNELS>()?;
+            let typ = blocks::Typ::<CHANNELS>::try_from(code)
+                .map_err(|_| Error::InvalidData("unknown block code"))?;
+            match typ {
+                blocks::Typ::Short
+                | blocks::Typ::Medium
+                | blocks::Typ::Long
+                | blocks::Typ::Wide => blocks::Pixel::<CHANNELS>::decode(encoded_value, code)?,
+                blocks::Typ::Gray => blocks::Gray::<CHANNELS>::decode(encoded_value),
+                blocks::Typ::Color => *self.palettes[x / self.tile_size]
+                    .get(encoded_value)
+                    .ok_or(Error::InvalidData("the color is not in the palette"))?,
+                blocks::Typ::Offset => {
+                    let extra_bits = self.offset_extra_bits;
+                    let idx = match extra_bits {
+                        0 => encoded_value,
+                        _ => (encoded_value << extra_bits) | buffer.next_n_bits(extra_bits)?,
+                    };
+                    self.offsets.get(idx)
                 }
-                0b01 => {
-                    let pixel = header.palette[encoded_value];
-                    offsets[pixel.hash()] = pixel;
-                    pixels.push(pixel);
-                    previous = pixel;
+                blocks::Typ::Repeating => {
+                    self.repeating = encoded_value;
+                    self.previous
+                }
+                blocks::Typ::Blob => self.next_blob(encoded_value, x, y, buffer)?,
+                blocks::Typ::Region => self.next_region(encoded_value, x, y, buffer)?,
+            }
+        };
+        self.offsets.push(pixel, self.info.hash.hash(&pixel));
+        self.previous = pixel;
+        self.index += 1;
+        Ok(pixel)
+    }
+
+    /// Drop the regions above the row `y` and gather the spans of the row covered by a region.
+    fn next_row(&mut self, y: usize) {
+        self.regions.retain(|region| region.y + region.height() > y);
+        self.spans.clear();
+        self.span_idx = 0;
+        for region in self.regions.iter() {
+            let spans = region.rows[y - region.y].iter();
+            self.spans.extend(spans.map(|span| (*span, region.fill)));
+        }
+        self.spans.sort_unstable_by_key(|(span, _)| span.0);
+    }
+
+    /// Return the value of the region covering the pixel at `x` in the current row, if any.
+    #[inline]
+    fn covering_fill(&mut self, x: usize) -> Option<img::Pixel<CHANNELS>> {
+        while self.span_idx < self.spans.len() && self.spans[self.span_idx].0 .1 <= x {
+            self.span_idx += 1;
+        }
+        match self.spans.get(self.span_idx) {
+            Some(((start, _), fill)) if *start <= x => Some(*fill),
+            _ => None,
+        }
+    }
+
+    /// Add a region starting at the current pixel and return its value.
+    fn add_region(&mut self, region: squares::Region<CHANNELS>) -> img::Pixel<CHANNELS> {
+        let fill = region.fill;
+        let spans = region.rows[0].iter();
+        self.spans.extend(spans.map(|span| (*span, fill)));
+        self.spans[self.span_idx..].sort_unstable_by_key(|(span, _)| span.0);
+        self.regions.push(region);
+        fill
+    }
+
+    /// Read the value of a blob starting at `(x, y)` and return it.
+    fn next_blob<R: std::io::Read>(
+        &mut self,
+        encoded_value: usize,
+        x: usize,
+        y: usize,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<img::Pixel<CHANNELS>, Error> {
+        let (width, height) = blocks::Blob::decode(encoded_value);
+        if x + width > self.info.width as usize || y + height > self.info.height as usize {
+            return Err(Error::InvalidData("the blob is outside of the image"));
+        }
+        let (code, encoded_value) = buffer.next_raw_block::<CHANNELS>()?;
+        let fill = blocks::Pixel::<CHANNELS>::decode(encoded_value, code)?;
+        Ok(self.add_region(squares::Region::from(squares::Blob {
+            fill,
+            x,
+            y,
+            width,
+            height,
+        })))
+    }
+
+    /// Read the value and the mask of a region starting at `(x, y)` and return its value.
+    fn next_region<R: std::io::Read>(
+        &mut self,
+        encoded_value: usize,
+        x: usize,
+        y: usize,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<img::Pixel<CHANNELS>, Error> {
+        let (width, height) = blocks::Region::<CHANNELS>::decode(encoded_value);
+        let (code, encoded_value) = buffer.next_raw_block::<CHANNELS>()?;
+        let fill = blocks::Pixel::<CHANNELS>::decode(encoded_value, code)?;
+        if y + height > self.info.height as usize {
+            return Err(Error::InvalidData("the region is outside of the image"));
+        }
+        let bits = blocks::Region::<CHANNELS>::run_bits(width);
+        let mut rows: Vec<Rc<[squares::Span]>> = Vec::with_capacity(height);
+        let mut left = 0;
+        for row in 0..height {
+            // A repeated row shares the spans of the previous one instead of copying them.
+            if row > 0 && buffer.next_n_bits(1)? == 1 {
+                rows.push(rows[row - 1].clone());
+                continue;
+            }
+            let mut spans = Vec::new();
+            let mut position = 0;
+            let mut is_inside = false;
+            while position < width {
+                let run = buffer.next_n_bits(bits)?;
+                // Only the first run can be empty, this makes sure that every row ends.
+                if position + run > width || (run == 0 && (position > 0 || is_inside)) {
+                    return Err(Error::InvalidData("invalid mask of a region"));
                 }
-                0b100 => {
-                    for _ in 0..(encoded_value + 1) {
-                        pixels.push(previous);
-                    }
+                if is_inside {
+                    spans.push((position, position + run));
                 }
-                0b111 => {
-                    let pixel = offsets[encoded_value];
-                    pixels.push(pixel);
-                    previous = pixel;
+                position += run;
+                is_inside = !is_inside;
+            }
+            if row == 0 {
+                // The region starts at the current pixel, the position of the mask is deduced
+                // from it.
+                left = match spans.first() {
+                    Some((start, _)) if *start <= x => x - start,
+                    _ => return Err(Error::InvalidData("invalid mask of a region")),
+                };
+                if left + width > self.info.width as usize {
+                    return Err(Error::InvalidData("the region is outside of the image"));
                 }
-                _ => panic!("The code does not exists"),
             }
+            spans
+                .iter_mut()
+                .for_each(|span| *span = (span.0 + left, span.1 + left));
+            rows.push(Rc::from(spans));
+        }
+        Ok(self.add_region(squares::Region { fill, y, rows }))
+    }
+}
+
+impl<R: std::io::Read, S: img::Sample, const CHANNELS: usize> RowDecoder<R> for State<S, CHANNELS> {
+    fn decode_row(
+        &mut self,
+        buffer: &mut buffer::BufferDecoder<R>,
+        row: &mut [u8],
+    ) -> Result<(), Error> {
+        let y = self.index / self.info.width as usize;
+        let is_tiles_row = self.info.tile_size > 0 && y.is_multiple_of(self.tile_size);
+        let is_adaptive = self.info.predictor == predictor::Predictor::Adaptive;
+        if (is_tiles_row || is_adaptive) && self.repeating > 0 {
+            return Err(Error::InvalidData(
+                "the repeating pixels span over the start of a row",
+            ));
+        }
+        if is_tiles_row {
+            let columns = (self.info.width as usize).div_ceil(self.tile_size);
+            self.palettes = (0..columns)
+                .map(|_| palette::Palette::<CHANNELS>::decode(buffer))
+                .collect::<Result<_, _>>()?;
+        }
+        if is_adaptive {
+            let tag = buffer.next_n_bits(predictor::Predictor::ROW_TAG_BITS)?;
+            self.row_predictor = predictor::Predictor::from_row_tag(tag)?;
+        }
+        for current in row.chunks_exact_mut(CHANNELS * S::BYTES) {
+            let pixel = self.next(buffer)?;
+            let prediction = self.neighbors.predict(self.row_predictor);
+            let mut samples = [S::default(); CHANNELS];
+            img::Pixel::<CHANNELS>::compute_backward(&prediction, &pixel, &mut samples);
+            img::write_samples(&samples, current);
+            self.neighbors.push(samples);
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        if self.repeating > 0 {
+            return Err(Error::InvalidData("too many pixels in the stream"));
         }
-        let mut bytes = Vec::with_capacity((header.width * header.height) as usize * CHANNELS);
-        let mut prev = vec![0; CHANNELS];
-        for pixel in pixels.into_iter() {
-            let mut current = img::Pixel::<CHANNELS>::compute_backward(&prev, &pixel);
-            prev = current.clone();
-            bytes.append(&mut current);
-        }
-        assert_eq!(
-            (header.width * header.height) as usize * CHANNELS,
-            bytes.len(),
-        );
-        (bytes, header.width, header.height)
+        Ok(())
     }
 }
@@ -1,120 +1,687 @@
 use crate::blocks;
 use crate::buffer;
+use crate::checksum;
+use crate::entropy;
+use crate::hash;
 use crate::img;
+use crate::metadata;
+use crate::offsets;
+use crate::palette;
+use crate::predictor;
 use crate::squares;
-use crate::tests::log;
+use crate::Error;
+
+/// Encode a raw sRGB image. The pixels are stored row by row with the channels interleaved,
+/// `raw` must therefore contain exactly `width * height * channels` bytes.
+pub fn encode(raw: &[u8], width: u32, height: u32, channels: usize) -> Result<Vec<u8>, Error> {
+    encode_image(raw, &img::ImageInfo::new(width, height, channels))
+}
+
+/// Encode a raw image described by `info`.
+pub fn encode_image(raw: &[u8], info: &img::ImageInfo) -> Result<Vec<u8>, Error> {
+    encode_with_metadata(raw, info, &metadata::Metadata::default())
+}
+
+/// Encode a raw image described by `info` and store `metadata` in its header.
+pub fn encode_with_metadata(
+    raw: &[u8],
+    info: &img::ImageInfo,
+    metadata: &metadata::Metadata,
+) -> Result<Vec<u8>, Error> {
+    encode_with_options(raw, info, metadata, &EncoderOptions::default())
+}
+
+/// Encode a raw image described by `info` with `metadata` in its header, `options` decide how
+/// much work goes into making the image smaller.
+pub fn encode_with_options(
+    raw: &[u8],
+    info: &img::ImageInfo,
+    metadata: &metadata::Metadata,
+    options: &EncoderOptions,
+) -> Result<Vec<u8>, Error> {
+    encode_with_observer(raw, info, metadata, options, &mut NoObserver)
+}
+
+/// Encode a raw image and report every block written to the stream to `observer`.
+pub fn encode_with_observer<O: Observer>(
+    raw: &[u8],
+    info: &img::ImageInfo,
+    metadata: &metadata::Metadata,
+    options: &EncoderOptions,
+    observer: &mut O,
+) -> Result<Vec<u8>, Error> {
+    info.validate()?;
+    let expected = info.raw_len()?;
+    if raw.len() != expected {
+        return Err(Error::InvalidBufferLength {
+            expected,
+            actual: raw.len(),
+        });
+    }
+    metadata.validate()?;
+    let info = &options.apply(info);
+    Ok(match info.bit_depth {
+        img::BitDepth::Eight => encode_samples::<u8, O>(raw, info, metadata, options, observer),
+        img::BitDepth::Sixteen => encode_samples::<u16, O>(raw, info, metadata, options, observer),
+    })
+}
+
+fn encode_samples<S: img::Sample, O: Observer>(
+    raw: &[u8],
+    info: &img::ImageInfo,
+    metadata: &metadata::Metadata,
+    options: &EncoderOptions,
+    observer: &mut O,
+) -> Vec<u8> {
+    match info.channels {
+        1 => Encoder::<1>::encode::<S, O>(raw, info, metadata, options, observer),
+        2 => Encoder::<2>::encode::<S, O>(raw, info, metadata, options, observer),
+        3 => Encoder::<3>::encode::<S, O>(raw, info, metadata, options, observer),
+        _ => Encoder::<4>::encode::<S, O>(raw, info, metadata, options, observer),
+    }
+}
+
+/// Presets of [`EncoderOptions`], from the fastest to the smallest output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Effort {
+    /// No blobs, no regions and a sampled palette.
+    Fastest,
+    /// Wide blobs only and a sampled palette.
+    Fast,
+    /// Blobs, regions and a sampled palette.
+    #[default]
+    Default,
+    /// Thinner blobs and smaller regions, a palette over every pixel, the adaptive predictor
+    /// choosing among the left, up and Paeth predictors, the FNV hash for the offsets and the
+    /// Huffman coding.
+    Small,
+    /// Everything is searched as much as possible, the offsets use the Murmur hash and the blocks
+    /// are coded with rANS.
+    Smallest,
+}
+
+/// The options of the encoder. They change how much work goes into making the image smaller, the
+/// predictor, the hash, the offsets and the entropy coding they choose are written in the header
+/// and replace the ones of the [`ImageInfo`](crate::ImageInfo). They are built with
+/// [`EncoderOptions::from_effort`] and the `with_` methods, new fields can be added by any release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EncoderOptions {
+    /// How the colors of the palettes are chosen.
+    pub palette: palette::PaletteEffort,
+    /// The rows of a blob are wider than this, `usize::MAX` disables the blobs.
+    pub blob_min_width: usize,
+    /// A blob is kept when it saves more than this number of bits over the repeating blocks.
+    pub blob_min_saved_bits: usize,
+    /// The regions of any shape have more pixels than this, `usize::MAX` disables the regions.
+    pub region_min_size: usize,
+    /// The predictor used instead of the one of the image info, if any. The header, and therefore
+    /// the decoded image info, contains the predictor actually used.
+    pub predictor: Option<predictor::Predictor>,
+    /// The hash function of the offsets used instead of the one of the image info, if any.
+    pub hash: Option<hash::HashFunction>,
+    /// The number of pixels and of ways of the cache of the offsets used instead of the ones of
+    /// the image info, if any.
+    pub offsets: Option<(u32, u32)>,
+    /// The entropy coding used instead of the one of the image info, if any. Only the whole
+    /// image encoders support an entropy coding, the [`StreamEncoder`] ignores this option.
+    pub entropy: Option<entropy::Entropy>,
+    /// The predictors tried on each row by the adaptive predictor.
+    pub row_predictors: &'static [predictor::Predictor],
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        Self::from_effort(Effort::Default)
+    }
+}
+
+impl EncoderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_effort(effort: Effort) -> Self {
+        let options = Self {
+            palette: palette::PaletteEffort::Sampled,
+            blob_min_width: 64,
+            blob_min_saved_bits: 71,
+            region_min_size: 10,
+            predictor: None,
+            hash: None,
+            offsets: None,
+            entropy: None,
+            row_predictors: &predictor::Predictor::ROWS,
+        };
+        match effort {
+            Effort::Fastest => Self {
+                blob_min_width: usize::MAX,
+                region_min_size: usize::MAX,
+                row_predictors: &[predictor::Predictor::Left, predictor::Predictor::Up],
+                ..options
+            },
+            Effort::Fast => Self {
+                region_min_size: usize::MAX,
+                row_predictors: &[
+                    predictor::Predictor::Left,
+                    predictor::Predictor::Up,
+                    predictor::Predictor::Paeth,
+                ],
+                ..options
+            },
+            Effort::Default => options,
+            Effort::Small => Self {
+                palette: palette::PaletteEffort::from_level(5),
+                blob_min_width: 32,
+                blob_min_saved_bits: 48,
+                region_min_size: 6,
+                predictor: Some(predictor::Predictor::Adaptive),
+                hash: Some(hash::HashFunction::Fnv),
+                offsets: Some((256, 4)),
+                entropy: Some(entropy::Entropy::Huffman),
+                row_predictors: &[
+                    predictor::Predictor::Left,
+                    predictor::Predictor::Up,
+                    predictor::Predictor::Paeth,
+                ],
+            },
+            Effort::Smallest => Self {
+                palette: palette::PaletteEffort::from_level(9),
+                blob_min_width: 16,
+                blob_min_saved_bits: 48,
+                region_min_size: 3,
+                predictor: Some(predictor::Predictor::Adaptive),
+                hash: Some(hash::HashFunction::Murmur),
+                offsets: Some((512, 8)),
+                entropy: Some(entropy::Entropy::Rans),
+                row_predictors: &predictor::Predictor::ROWS,
+            },
+        }
+    }
+
+    pub fn with_palette_effort(mut self, palette: palette::PaletteEffort) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    pub fn with_predictor(mut self, predictor: predictor::Predictor) -> Self {
+        self.predictor = Some(predictor);
+        self
+    }
+
+    pub fn with_hash(mut self, hash: hash::HashFunction) -> Self {
+        self.hash = Some(hash);
+        self
+    }
+
+    pub fn with_offsets(mut self, offsets: u32, ways: u32) -> Self {
+        self.offsets = Some((offsets, ways));
+        self
+    }
+
+    pub fn with_entropy(mut self, entropy: entropy::Entropy) -> Self {
+        self.entropy = Some(entropy);
+        self
+    }
+
+    /// Return the description of the image that is stored in the header.
+    fn apply(&self, info: &img::ImageInfo) -> img::ImageInfo {
+        let info = self
+            .predictor
+            .map_or(*info, |predictor| info.with_predictor(predictor));
+        let info = self.hash.map_or(info, |hash| info.with_hash(hash));
+        let info = self
+            .offsets
+            .map_or(info, |(offsets, ways)| info.with_offsets(offsets, ways));
+        self.entropy
+            .map_or(info, |entropy| info.with_entropy(entropy))
+    }
+}
+
+/// Receive every block pushed to the stream by the encoder, after the header. It is used to
+/// gather statistics about the encoded images. The value of a blob or a region and the mask of a
+/// region belong to the block that they follow, they are not reported on their own.
+pub trait Observer {
+    fn block<const CHANNELS: usize>(&mut self, typ: blocks::Typ<CHANNELS>, block: &blocks::Block);
+
+    /// Called when a pixel is looked up in the cache of the offsets, `hit` is true when it is
+    /// found. Only the pixels that are not repeating, gray or in the palette are looked up.
+    #[inline(always)]
+    fn offset(&mut self, _hit: bool) {}
+}
+
+/// An observer that ignores the blocks, it is optimized away.
+pub struct NoObserver;
+
+impl Observer for NoObserver {
+    #[inline(always)]
+    fn block<const CHANNELS: usize>(&mut self, _: blocks::Typ<CHANNELS>, _: &blocks::Block) {}
+}
 
 pub struct Encoder<const CHANNELS: usize>;
 
 impl<const CHANNELS: usize> Encoder<CHANNELS> {
-    pub fn encode_with_logger(raw: &[u8], width: usize, height: usize) -> (log::Logger, Vec<u8>) {
-        let image = img::Image::<CHANNELS>::new(raw, width, height);
-
-        let mut blobs = squares::Blobs::<CHANNELS>::new(raw, width, height);
-        blobs.compute();
-        println!("blobs: {}", blobs.blobs.len());
-
-        let header = img::Header::new(width, height, &image.palette);
-        let mut offsets = [img::Pixel::zeros(); blocks::Offset::MAX];
-        let mut repeating = 0;
-        let mut previous_hash = 0;
-        let mut previous_chunk = &[0; CHANNELS][..];
-        let mut logger = log::Logger::new();
+    pub fn encode<S: img::Sample, O: Observer>(
+        raw: &[u8],
+        info: &img::ImageInfo,
+        metadata: &metadata::Metadata,
+        options: &EncoderOptions,
+        observer: &mut O,
+    ) -> Vec<u8> {
+        let (width, height) = (info.width as usize, info.height as usize);
+        let (pixels, row_predictors) = predictor::compute_pixels::<S, CHANNELS>(
+            raw,
+            width,
+            info.predictor,
+            options.row_predictors,
+        );
+        let (palette, palettes) = match info.tile_size {
+            0 => {
+                let image = img::Image::<CHANNELS>::new(&pixels, width, height, options.palette);
+                (image.palette.clone(), palette::Tiles::single(image.palette))
+            }
+            size => {
+                let palettes = palette::Tiles::new(width, size as usize, options.palette);
+                (palette::Palette::new(Vec::new()), palettes)
+            }
+        };
+        let blobs = squares::Blobs::new(&pixels, width, options);
+        let tables = match info.entropy {
+            // rANS adapts its models while the image is coded.
+            entropy::Entropy::Raw | entropy::Entropy::Rans => None,
+            entropy::Entropy::Huffman => {
+                // The codes are built from the blocks of a first encoding of the image.
+                let mut statistics = entropy::Statistics::new();
+                let mut state =
+                    State::<S, CHANNELS>::new(info, options, palettes.clone(), blobs.clone())
+                        .with_chosen_predictors(row_predictors.clone());
+                let mut buffer = buffer::Buffer::new(raw.len());
+                state.push_image(raw, &pixels, &mut buffer, &mut statistics);
+                Some(entropy::Tables::new::<CHANNELS>(&statistics))
+            }
+        };
+        let header = img::Header::new(*info, metadata.clone(), &palette, tables);
         let mut buffer = buffer::Buffer::from(header);
+        let mut state = State::<S, CHANNELS>::new(info, options, palettes, blobs)
+            .with_chosen_predictors(row_predictors);
+        state.push_image(raw, &pixels, &mut buffer, observer);
+        buffer.push_checksum(checksum::Crc32::compute(raw));
+        buffer.bytes
+    }
+}
 
-        for (idx, current) in raw.chunks_exact(CHANNELS).enumerate() {
-            let pixel = img::Pixel::<CHANNELS>::compute_forward(previous_chunk, current);
-            let hashed = pixel.hash();
-
-            if !blobs.is_inside_blob(idx) {
-                if offsets[previous_hash] == pixel {
-                    if repeating < blocks::Repeating::MAX {
-                        repeating += 1;
-                    } else {
-                        buffer.push(blocks::Repeating::encode(repeating));
-                        logger.repeating += 1;
-                        repeating = 1;
-                    }
-                } else {
-                    if repeating > 0 {
-                        buffer.push(blocks::Repeating::encode(repeating));
-                        logger.repeating += 1;
-                        repeating = 0;
-                    }
-                    if blocks::Gray::is_gray(&pixel) {
-                        buffer.push(blocks::Gray::encode(&pixel));
-                        logger.gray += 1;
-                    } else if let Some(color) = image.palette.get(&pixel) {
-                        buffer.push(blocks::Color::encode(color));
-                        logger.palette += 1;
-                    } else if offsets[hashed] == pixel {
-                        buffer.push(blocks::Offset::encode(hashed));
-                        logger.offset += 1;
-                    } else {
-                        let block = blocks::Pixel::encode(&pixel);
-                        if block.bit_count == blocks::Typ::<CHANNELS>::Short.size() {
-                            logger.short += 1;
-                        } else if block.bit_count == blocks::Typ::<CHANNELS>::Medium.size() {
-                            logger.medium += 1;
-                        } else if block.bit_count == blocks::Typ::<CHANNELS>::Long.size() {
-                            logger.long += 1;
-                        }
-                        buffer.push(block);
-                    }
-                }
+/// The state of the encoder between two pixels. The pixels are pushed one by one in the order
+/// they appear in the image.
+pub struct State<S: img::Sample, const CHANNELS: usize> {
+    width: usize,
+    tile_size: usize,
+    palettes: palette::Tiles<CHANNELS>,
+    blobs: squares::Blobs<CHANNELS>,
+    offsets: offsets::Offsets<CHANNELS>,
+    /// The number of pixels of the cache of the offsets.
+    offsets_len: usize,
+    repeating: usize,
+    previous: img::Pixel<CHANNELS>,
+    /// The hash function of the cache of the offsets.
+    hash: hash::HashFunction,
+    predictor: predictor::Predictor,
+    /// The predictor of the current row, it is only different from `predictor` when the latter is
+    /// adaptive.
+    row_predictor: predictor::Predictor,
+    /// The predictors tried on each row by the adaptive predictor.
+    row_predictors: &'static [predictor::Predictor],
+    /// The predictor of each row when they are chosen before the image is pushed, empty when the
+    /// adaptive predictor chooses them as the rows are pushed.
+    chosen_predictors: Vec<predictor::Predictor>,
+    neighbors: predictor::Neighbors<S, CHANNELS>,
+    /// The index of the next pixel in the image.
+    index: usize,
+}
+
+impl<S: img::Sample, const CHANNELS: usize> State<S, CHANNELS> {
+    pub fn new(
+        info: &img::ImageInfo,
+        options: &EncoderOptions,
+        palettes: palette::Tiles<CHANNELS>,
+        blobs: squares::Blobs<CHANNELS>,
+    ) -> Self {
+        Self {
+            width: info.width as usize,
+            tile_size: info.tile_size as usize,
+            palettes,
+            blobs,
+            offsets: offsets::Offsets::new(info.offsets as usize, info.offset_ways as usize),
+            offsets_len: info.offsets as usize,
+            repeating: 0,
+            previous: img::Pixel::zeros(),
+            hash: info.hash,
+            predictor: info.predictor,
+            row_predictor: info.predictor,
+            row_predictors: options.row_predictors,
+            chosen_predictors: Vec::new(),
+            neighbors: predictor::Neighbors::new(info.width as usize),
+            index: 0,
+        }
+    }
+
+    /// Use the predictors of the rows returned by [`predictor::compute_pixels`] instead of
+    /// choosing them again.
+    pub fn with_chosen_predictors(mut self, predictors: Vec<predictor::Predictor>) -> Self {
+        self.chosen_predictors = predictors;
+        self
+    }
+
+    /// Push the next row, `row` contains the raw bytes of the row. The palettes of the tiles
+    /// starting at this row and the tag of the row with the adaptive predictor are pushed first.
+    /// The repeating pixels cannot span over them.
+    pub fn push_row<O: Observer>(
+        &mut self,
+        row: &[u8],
+        buffer: &mut buffer::Buffer,
+        observer: &mut O,
+    ) {
+        let y = self.index / self.width;
+        if self.tile_size > 0 && y.is_multiple_of(self.tile_size) {
+            self.push_repeating(buffer, observer);
+            for palette in self.palettes.row() {
+                palette.encode(buffer);
             }
-            offsets[hashed] = pixel;
-            previous_hash = hashed;
-            previous_chunk = current;
         }
-        if repeating > 0 {
-            buffer.push(blocks::Repeating::encode(repeating));
-            logger.repeating += 1;
+        if self.predictor == predictor::Predictor::Adaptive {
+            self.push_repeating(buffer, observer);
+            self.row_predictor = match self.chosen_predictors.get(y) {
+                Some(predictor) => *predictor,
+                None => self.neighbors.choose(row, self.row_predictors),
+            };
+            let tag = self.row_predictor as usize;
+            buffer.push(blocks::Block::new(predictor::Predictor::ROW_TAG_BITS, tag));
+        }
+        for current in row.chunks_exact(CHANNELS * S::BYTES) {
+            self.push(current, buffer, observer);
         }
-        buffer.bytes.append(&mut vec![0; blobs.size()]);
-        (logger, buffer.bytes)
     }
 
-    pub fn encode(raw: &[u8], width: usize, height: usize) -> Vec<u8> {
-        let image = img::Image::new(raw, width, height);
-        let header = img::Header::new(width, height, &image.palette);
-        let mut offsets = [img::Pixel::zeros(); blocks::Offset::MAX];
-        let mut repeating = 0;
-        let mut previous_hash = 0;
-        let mut previous_chunk = &[0; CHANNELS][..];
-        let mut buffer = buffer::Buffer::from(header);
+    /// Push the next pixel, `current` contains the raw bytes of the pixel.
+    #[inline]
+    fn push<O: Observer>(&mut self, current: &[u8], buffer: &mut buffer::Buffer, observer: &mut O) {
+        let current = img::read_samples::<S, CHANNELS>(current);
+        let prediction = self.neighbors.predict(self.row_predictor);
+        let pixel = img::Pixel::<CHANNELS>::compute_forward(&prediction, &current);
+        let hash = self.hash.hash(&pixel);
+        let cached = self.offsets.push(pixel, hash);
 
-        for current in raw.chunks_exact(CHANNELS) {
-            let pixel = img::Pixel::<CHANNELS>::compute_forward(previous_chunk, current);
-            let hashed = pixel.hash();
-
-            if offsets[previous_hash] == pixel {
-                if repeating < blocks::Repeating::MAX {
-                    repeating += 1;
-                } else {
-                    buffer.push(blocks::Repeating::encode(repeating));
-                    repeating = 1;
+        if let Some((region, is_first)) = self.blobs.at(self.index) {
+            // The pixels of a region are not encoded, the repeating pixels cannot span over them.
+            let region_blocks = is_first.then(|| Self::encode_region(region));
+            self.push_repeating(buffer, observer);
+            if let Some((typ, region_blocks)) = region_blocks {
+                let mut region_blocks = region_blocks.into_iter();
+                if let Some(block) = region_blocks.next() {
+                    Self::push_block(typ, block, buffer, observer);
                 }
+                region_blocks.for_each(|block| buffer.push(block));
+            }
+        } else if self.previous == pixel {
+            if self.repeating < blocks::Repeating::MAX {
+                self.repeating += 1;
             } else {
-                if repeating > 0 {
-                    buffer.push(blocks::Repeating::encode(repeating));
-                    repeating = 0;
-                }
-                if let Some(color) = image.palette.get(&pixel) {
-                    buffer.push(blocks::Color::encode(color));
-                } else if offsets[hashed] == pixel {
-                    buffer.push(blocks::Offset::encode(hashed));
-                } else {
-                    buffer.push(blocks::Pixel::encode(&pixel));
-                }
+                let block = blocks::Repeating::encode(self.repeating);
+                Self::push_block(blocks::Typ::Repeating, block, buffer, observer);
+                self.repeating = 1;
+            }
+        } else {
+            self.push_repeating(buffer, observer);
+            if blocks::Gray::is_gray(&pixel) {
+                let block = blocks::Gray::encode(&pixel);
+                Self::push_block(blocks::Typ::Gray, block, buffer, observer);
+            } else if let Some(color) = self
+                .palettes
+                .get(self.index % self.width)
+                .get(&pixel, self.palette_hash(&pixel, hash))
+            {
+                let block = blocks::Color::encode(color);
+                Self::push_block(blocks::Typ::Color, block, buffer, observer);
+            } else if let Some(idx) = cached {
+                observer.offset(true);
+                let block = blocks::Offset::encode(idx, self.offsets_len);
+                Self::push_block(blocks::Typ::Offset, block, buffer, observer);
+            } else {
+                observer.offset(false);
+                let (typ, block) = blocks::Pixel::encode_with_typ(&pixel);
+                Self::push_block(typ, block, buffer, observer);
             }
-            offsets[hashed] = pixel;
-            previous_hash = hashed;
-            previous_chunk = current;
         }
-        if repeating > 0 {
-            buffer.push(blocks::Repeating::encode(repeating));
+        self.previous = pixel;
+        self.neighbors.push(current);
+        self.index += 1;
+    }
+
+    /// Return the hash of `pixel` in the palettes, always the one of qoi. `hash` is the hash of
+    /// `pixel` in the offsets, it is reused when the offsets use the hash of qoi too.
+    #[inline]
+    fn palette_hash(&self, pixel: &img::Pixel<CHANNELS>, hash: usize) -> usize {
+        match self.hash {
+            hash::HashFunction::Qoi => hash,
+            _ => pixel.hash(),
         }
-        buffer.bytes
+    }
+
+    /// Push every row of the image, `raw`, and the pending blocks after its last pixel. The
+    /// palettes of each row of tiles are computed from the value of its `pixels` when it starts.
+    fn push_image<O: Observer>(
+        &mut self,
+        raw: &[u8],
+        pixels: &[img::Pixel<CHANNELS>],
+        buffer: &mut buffer::Buffer,
+        observer: &mut O,
+    ) {
+        for (y, row) in raw
+            .chunks_exact(self.width * CHANNELS * S::BYTES)
+            .enumerate()
+        {
+            if self.tile_size > 0 && y.is_multiple_of(self.tile_size) {
+                let end = pixels.len().min((y + self.tile_size) * self.width);
+                self.palettes.compute_row(&pixels[y * self.width..end]);
+            }
+            self.push_row(row, buffer, observer);
+        }
+        self.finish(buffer, observer);
+    }
+
+    /// Push the pending blocks after the last pixel of the image.
+    pub fn finish<O: Observer>(&mut self, buffer: &mut buffer::Buffer, observer: &mut O) {
+        self.push_repeating(buffer, observer);
+    }
+
+    /// Return the blocks of a region, a `Blob` block is used for the rectangles.
+    fn encode_region(
+        region: &squares::Region<CHANNELS>,
+    ) -> (blocks::Typ<CHANNELS>, Vec<blocks::Block>) {
+        if region.is_rectangle() {
+            let (left, right) = region.bounds();
+            let region_blocks = vec![
+                blocks::Blob::encode(right - left, region.height()),
+                blocks::Pixel::encode(&region.fill),
+            ];
+            (blocks::Typ::Blob, region_blocks)
+        } else {
+            (blocks::Typ::Region, blocks::Region::encode(region))
+        }
+    }
+
+    /// Push the pending repeating pixels, if any.
+    #[inline]
+    fn push_repeating<O: Observer>(&mut self, buffer: &mut buffer::Buffer, observer: &mut O) {
+        if self.repeating > 0 {
+            let block = blocks::Repeating::encode(self.repeating);
+            Self::push_block(blocks::Typ::Repeating, block, buffer, observer);
+            self.repeating = 0;
+        }
+    }
+
+    #[inline]
+    fn push_block<O: Observer>(
+        typ: blocks::Typ<CHANNELS>,
+        block: blocks::Block,
+        buffer: &mut buffer::Buffer,
+        observer: &mut O,
+    ) {
+        let code = typ.code();
+        observer.block(typ, &block);
+        buffer.push_block::<CHANNELS>(code, block);
+    }
+}
+
+/// Encode the rows of an image when the number of channels is only known at runtime.
+trait RowEncoder {
+    fn push_row(&mut self, row: &[u8], buffer: &mut buffer::Buffer);
+    fn finish(&mut self, buffer: &mut buffer::Buffer);
+}
+
+impl<S: img::Sample, const CHANNELS: usize> RowEncoder for State<S, CHANNELS> {
+    fn push_row(&mut self, row: &[u8], buffer: &mut buffer::Buffer) {
+        State::push_row(self, row, buffer, &mut NoObserver);
+    }
+
+    fn finish(&mut self, buffer: &mut buffer::Buffer) {
+        State::finish(self, buffer, &mut NoObserver);
+    }
+}
+
+/// An encoder that receives the image row by row and writes the encoded bytes to `writer` as soon
+/// as they are complete. Only a row and the offsets are kept in memory, the image is therefore
+/// encoded without palettes nor blobs.
+pub struct StreamEncoder<W: std::io::Write> {
+    writer: W,
+    info: img::ImageInfo,
+    buffer: buffer::Buffer,
+    state: Box<dyn RowEncoder>,
+    checksum: checksum::Crc32,
+    rows: u32,
+}
+
+impl<W: std::io::Write> StreamEncoder<W> {
+    /// Create the encoder and write the header of the image.
+    pub fn new(writer: W, info: img::ImageInfo) -> Result<Self, Error> {
+        Self::with_metadata(writer, info, &metadata::Metadata::default())
+    }
+
+    /// Create the encoder and write the header of the image with `metadata`.
+    pub fn with_metadata(
+        writer: W,
+        info: img::ImageInfo,
+        metadata: &metadata::Metadata,
+    ) -> Result<Self, Error> {
+        Self::with_options(writer, info, metadata, &EncoderOptions::default())
+    }
+
+    /// Create the encoder and write the header of the image with `metadata`. Only the options
+    /// that apply to a single row are used: there are no palettes, no blobs and no entropy coding
+    /// in a stream. The entropy coding of `options` is therefore ignored and the blocks are
+    /// written as is, so that every [`Effort`] can be streamed, but the entropy coding of `info`
+    /// is an error.
+    pub fn with_options(
+        writer: W,
+        info: img::ImageInfo,
+        metadata: &metadata::Metadata,
+        options: &EncoderOptions,
+    ) -> Result<Self, Error> {
+        let options = EncoderOptions {
+            entropy: None,
+            ..*options
+        };
+        let info = options.apply(&info);
+        info.validate()?;
+        metadata.validate()?;
+        if info.entropy != entropy::Entropy::Raw {
+            // The Huffman codes are built from the whole image before the header is written and
+            // rANS encodes the image backward.
+            return Err(Error::InvalidData(
+                "the entropy coding is not supported by the stream encoder",
+            ));
+        }
+        let mut buffer = buffer::Buffer::new(info.row_len());
+        let state = match info.bit_depth {
+            img::BitDepth::Eight => Self::init::<u8>(info, metadata, &options, &mut buffer),
+            img::BitDepth::Sixteen => Self::init::<u16>(info, metadata, &options, &mut buffer),
+        };
+        let mut encoder = Self {
+            writer,
+            info,
+            buffer,
+            state,
+            checksum: checksum::Crc32::new(),
+            rows: 0,
+        };
+        encoder.buffer.flush(&mut encoder.writer)?;
+        Ok(encoder)
+    }
+
+    fn init<S: img::Sample>(
+        info: img::ImageInfo,
+        metadata: &metadata::Metadata,
+        options: &EncoderOptions,
+        buffer: &mut buffer::Buffer,
+    ) -> Box<dyn RowEncoder> {
+        match info.channels {
+            1 => Self::init_channels::<S, 1>(info, metadata, options, buffer),
+            2 => Self::init_channels::<S, 2>(info, metadata, options, buffer),
+            3 => Self::init_channels::<S, 3>(info, metadata, options, buffer),
+            _ => Self::init_channels::<S, 4>(info, metadata, options, buffer),
+        }
+    }
+
+    fn init_channels<S: img::Sample, const CHANNELS: usize>(
+        info: img::ImageInfo,
+        metadata: &metadata::Metadata,
+        options: &EncoderOptions,
+        buffer: &mut buffer::Buffer,
+    ) -> Box<dyn RowEncoder> {
+        let palette = palette::Palette::<CHANNELS>::new(Vec::new());
+        buffer.push_header(img::Header::new(info, metadata.clone(), &palette, None));
+        let palettes = match info.tile_size {
+            0 => palette::Tiles::single(palette),
+            size => palette::Tiles::new(info.width as usize, size as usize, options.palette),
+        };
+        Box::new(State::<S, CHANNELS>::new(
+            &info,
+            options,
+            palettes,
+            squares::Blobs::default(),
+        ))
+    }
+
+    /// Encode the next row of the image. `row` must contain exactly `width * channels` samples.
+    pub fn write_row(&mut self, row: &[u8]) -> Result<(), Error> {
+        let expected = self.info.row_len();
+        if row.len() != expected {
+            return Err(Error::InvalidBufferLength {
+                expected,
+                actual: row.len(),
+            });
+        }
+        if self.rows == self.info.height {
+            return Err(Error::InvalidData("all the rows were already written"));
+        }
+        self.state.push_row(row, &mut self.buffer);
+        self.checksum.update(row);
+        self.rows += 1;
+        self.buffer.flush(&mut self.writer)?;
+        Ok(())
+    }
+
+    /// Write the last bytes of the image and return the writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.rows != self.info.height {
+            return Err(Error::MissingRows {
+                expected: self.info.height,
+                actual: self.rows,
+            });
+        }
+        self.state.finish(&mut self.buffer);
+        self.buffer.push_checksum(self.checksum.value());
+        self.writer.write_all(&self.buffer.bytes)?;
+        self.writer.flush()?;
+        Ok(self.writer)
     }
 }
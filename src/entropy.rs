@@ -0,0 +1,416 @@
+use crate::blocks;
+use crate::buffer;
+use crate::encoder;
+use crate::Error;
+
+/// How the blocks following the header are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Entropy {
+    /// The blocks are written as is: their prefix code and their payload.
+    #[default]
+    Raw,
+    /// The types of the blocks and the small fields of their payload are replaced by canonical
+    /// Huffman codes built for the image, see [`Tables`].
+    Huffman,
+    /// The blocks and everything following the header are coded with rANS and adaptive models,
+    /// see [`crate::rans`]. It is the smallest and the slowest, and it cannot be streamed.
+    Rans,
+}
+
+impl TryFrom<usize> for Entropy {
+    type Error = Error;
+
+    fn try_from(value: usize) -> Result<Self, Error> {
+        match value {
+            0 => Ok(Self::Raw),
+            1 => Ok(Self::Huffman),
+            2 => Ok(Self::Rans),
+            _ => Err(Error::InvalidData("unknown entropy coding")),
+        }
+    }
+}
+
+/// The longest Huffman code, the decoding tables have `2^MAX_CODE_LEN` entries at most.
+pub const MAX_CODE_LEN: usize = 12;
+/// The fields of a payload wider than this are not entropy coded.
+pub const MAX_FIELD_BITS: usize = 8;
+/// Number of bits of a code length in the header.
+const LEN_BITS: usize = 4;
+/// Number of types of blocks, the symbols of the types are `0..TYPES`.
+pub const TYPES: usize = blocks::Typ::<0>::CODES.len();
+
+/// A canonical Huffman code: the codes are assigned in the order of their length, then of their
+/// symbol, only the length of the code of each symbol is therefore stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    lengths: Vec<u8>,
+    codes: Vec<u16>,
+    /// The symbol and the length of the code starting with each value of `lookup_bits` bits, the
+    /// length is 0 for the values that do not start with a code.
+    lookup: Vec<(u16, u8)>,
+    lookup_bits: usize,
+}
+
+impl Code {
+    /// Build the code of the symbols appearing `counts` times, no code is longer than
+    /// [`MAX_CODE_LEN`].
+    pub fn from_counts(counts: &[usize]) -> Self {
+        let mut counts = counts.to_vec();
+        loop {
+            let lengths = Self::depths(&counts);
+            if lengths.iter().all(|len| *len as usize <= MAX_CODE_LEN) {
+                return Self::from_lengths(lengths)
+                    .expect("the lengths of a Huffman code are valid");
+            }
+            // Flatten the distribution until the tree is shallow enough.
+            counts
+                .iter_mut()
+                .for_each(|count| *count = count.div_ceil(2));
+        }
+    }
+
+    /// Return the depth of each symbol in the Huffman tree of `counts`, 0 for the symbols that
+    /// never appear. A symbol appearing alone still gets a code of 1 bit.
+    fn depths(counts: &[usize]) -> Vec<u8> {
+        let mut lengths = vec![0; counts.len()];
+        let symbols = (0..counts.len())
+            .filter(|symbol| counts[*symbol] > 0)
+            .collect::<Vec<_>>();
+        if symbols.len() == 1 {
+            lengths[symbols[0]] = 1;
+            return lengths;
+        }
+        // The nodes of the tree: the leaves first, then the internal nodes as they are created.
+        let mut parents = vec![usize::MAX; symbols.len()];
+        let mut heap = symbols
+            .iter()
+            .enumerate()
+            .map(|(node, symbol)| std::cmp::Reverse((counts[*symbol], node)))
+            .collect::<std::collections::BinaryHeap<_>>();
+        while heap.len() > 1 {
+            let std::cmp::Reverse((left_count, left)) = heap.pop().unwrap();
+            let std::cmp::Reverse((right_count, right)) = heap.pop().unwrap();
+            let node = parents.len();
+            parents.push(usize::MAX);
+            parents[left] = node;
+            parents[right] = node;
+            heap.push(std::cmp::Reverse((left_count + right_count, node)));
+        }
+        for (leaf, symbol) in symbols.iter().enumerate() {
+            let mut node = leaf;
+            while parents[node] != usize::MAX {
+                node = parents[node];
+                lengths[*symbol] += 1;
+            }
+        }
+        lengths
+    }
+
+    /// Build the code from the length of the code of each symbol. Fails if the lengths cannot be
+    /// the lengths of a prefix code.
+    pub fn from_lengths(lengths: Vec<u8>) -> Result<Self, Error> {
+        let lookup_bits = lengths.iter().copied().max().unwrap_or(0) as usize;
+        if lookup_bits > MAX_CODE_LEN {
+            return Err(Error::InvalidData("the Huffman code is too long"));
+        }
+        // Kraft's inequality: the codes must fit in the `2^lookup_bits` values.
+        let used = lengths
+            .iter()
+            .filter(|len| **len > 0)
+            .map(|len| 1usize << (lookup_bits - *len as usize))
+            .sum::<usize>();
+        if used > 1 << lookup_bits {
+            return Err(Error::InvalidData("the Huffman code is not a prefix code"));
+        }
+        let mut symbols = (0..lengths.len())
+            .filter(|symbol| lengths[*symbol] > 0)
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|symbol| (lengths[*symbol], *symbol));
+        let mut codes = vec![0; lengths.len()];
+        let mut lookup = vec![(0, 0); 1 << lookup_bits];
+        let (mut code, mut previous_len) = (0u16, 0);
+        for symbol in symbols {
+            let len = lengths[symbol];
+            code <<= len - previous_len;
+            codes[symbol] = code;
+            let shift = lookup_bits - len as usize;
+            let start = (code as usize) << shift;
+            lookup[start..start + (1 << shift)].fill((symbol as u16, len));
+            (code, previous_len) = (code + 1, len);
+        }
+        Ok(Self {
+            lengths,
+            codes,
+            lookup,
+            lookup_bits,
+        })
+    }
+
+    #[inline]
+    pub fn encode(&self, symbol: usize, buffer: &mut buffer::Buffer) {
+        let len = self.lengths[symbol] as usize;
+        buffer.push(blocks::Block::new(len, self.codes[symbol] as usize));
+    }
+
+    #[inline]
+    pub fn decode<R: std::io::Read>(
+        &self,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<usize, Error> {
+        let (symbol, len) = self.lookup[buffer.peek_n_bits(self.lookup_bits)?];
+        if len == 0 {
+            return Err(Error::InvalidData("unknown Huffman code"));
+        }
+        buffer.next_n_bits(len as usize)?;
+        Ok(symbol as usize)
+    }
+
+    /// Write the lengths of the codes. A length of 0 is followed by the number of other symbols
+    /// without a code that follow it, up to 15.
+    pub fn write(&self, buffer: &mut buffer::Buffer) {
+        let mut symbol = 0;
+        while symbol < self.lengths.len() {
+            let len = self.lengths[symbol] as usize;
+            buffer.push(blocks::Block::new(LEN_BITS, len));
+            symbol += 1;
+            if len == 0 {
+                let max_run = (1 << LEN_BITS) - 1;
+                let run = self.lengths[symbol..]
+                    .iter()
+                    .take(max_run)
+                    .take_while(|len| **len == 0)
+                    .count();
+                buffer.push(blocks::Block::new(LEN_BITS, run));
+                symbol += run;
+            }
+        }
+    }
+
+    /// Read the lengths of the codes of `symbols` symbols written by [`Code::write`].
+    pub fn read<R: std::io::Read>(
+        symbols: usize,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<Self, Error> {
+        let mut lengths = Vec::with_capacity(symbols);
+        while lengths.len() < symbols {
+            let len = buffer.next_n_bits(LEN_BITS)? as u8;
+            lengths.push(len);
+            if len == 0 {
+                let run = buffer.next_n_bits(LEN_BITS)?;
+                lengths.resize(lengths.len() + run, 0);
+            }
+        }
+        if lengths.len() > symbols {
+            return Err(Error::InvalidData("too many Huffman code lengths"));
+        }
+        Self::from_lengths(lengths)
+    }
+
+    /// Return the length of the code of each symbol.
+    pub fn lengths(&self) -> &[u8] {
+        &self.lengths
+    }
+}
+
+/// Return the symbol of the type of block with the prefix code `code`.
+#[inline]
+pub fn symbol(code: usize) -> usize {
+    blocks::Typ::<0>::CODES
+        .iter()
+        .position(|typ_code| *typ_code == code)
+        .expect("the code of a block is known")
+}
+
+/// Split the payload of a block into its entropy coded part, which has the size given by
+/// [`blocks::Typ::block_len`], and the bits that follow it. Only the `Offset` blocks of the
+/// largest caches have such bits.
+#[inline]
+pub fn split<const CHANNELS: usize>(code: usize, block: &blocks::Block) -> (usize, usize, usize) {
+    let payload_bits = block.bit_count - blocks::Typ::<0>::code_len(code);
+    let payload = block.value & ((1 << payload_bits) - 1);
+    let extra_bits = payload_bits - blocks::Typ::<CHANNELS>::block_len(code);
+    let extra = payload & ((1 << extra_bits) - 1);
+    ((payload >> extra_bits) as usize, extra as usize, extra_bits)
+}
+
+/// Return the fields of `payload`, the first one is in the most significant bits.
+#[inline]
+pub fn fields(payload: usize, bits: usize, count: usize) -> impl Iterator<Item = usize> {
+    (0..count).map(move |idx| (payload >> (bits * (count - 1 - idx))) & ((1 << bits) - 1))
+}
+
+/// Number of times each type of block and each value of their fields appear in an image. It is
+/// gathered by encoding the image a first time.
+pub struct Statistics {
+    types: [usize; TYPES],
+    fields: [Vec<usize>; TYPES],
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            types: [0; TYPES],
+            fields: std::array::from_fn(|_| Vec::new()),
+        }
+    }
+}
+
+impl encoder::Observer for Statistics {
+    fn block<const CHANNELS: usize>(&mut self, typ: blocks::Typ<CHANNELS>, block: &blocks::Block) {
+        let code = typ.code();
+        let symbol = symbol(code);
+        self.types[symbol] += 1;
+        let (bits, count) = blocks::Typ::<CHANNELS>::fields(code);
+        if bits <= MAX_FIELD_BITS {
+            let counts = &mut self.fields[symbol];
+            counts.resize(1 << bits, 0);
+            let (payload, _, _) = split::<CHANNELS>(code, block);
+            fields(payload, bits, count).for_each(|field| counts[field] += 1);
+        }
+    }
+}
+
+/// The Huffman codes of an image: one for the types of the blocks and one for the fields of each
+/// type whose fields are at most [`MAX_FIELD_BITS`] bits wide. The other payloads and everything
+/// that is not a block (the palettes of the tiles, the tags of the rows, the value and the mask of
+/// the regions...) are written as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tables {
+    types: Code,
+    fields: Vec<Option<Code>>,
+}
+
+impl Tables {
+    pub fn new<const CHANNELS: usize>(statistics: &Statistics) -> Self {
+        let fields = blocks::Typ::<CHANNELS>::CODES
+            .iter()
+            .enumerate()
+            .map(|(symbol, code)| {
+                let (bits, _) = blocks::Typ::<CHANNELS>::fields(*code);
+                let is_coded = statistics.types[symbol] > 0 && bits <= MAX_FIELD_BITS;
+                is_coded.then(|| Code::from_counts(&statistics.fields[symbol]))
+            })
+            .collect();
+        Self {
+            types: Code::from_counts(&statistics.types),
+            fields,
+        }
+    }
+
+    /// Push a block with the prefix code `code`.
+    #[inline]
+    pub fn encode<const CHANNELS: usize>(
+        &self,
+        code: usize,
+        block: blocks::Block,
+        buffer: &mut buffer::Buffer,
+    ) {
+        let symbol = symbol(code);
+        self.types.encode(symbol, buffer);
+        let (payload, extra, extra_bits) = split::<CHANNELS>(code, &block);
+        match &self.fields[symbol] {
+            Some(table) => {
+                let (bits, count) = blocks::Typ::<CHANNELS>::fields(code);
+                fields(payload, bits, count).for_each(|field| table.encode(field, buffer));
+            }
+            None => buffer.push(blocks::Block::new(
+                blocks::Typ::<CHANNELS>::block_len(code),
+                payload,
+            )),
+        }
+        if extra_bits > 0 {
+            buffer.push(blocks::Block::new(extra_bits, extra));
+        }
+    }
+
+    /// Read the next block and return its prefix code and its payload, like
+    /// [`buffer::BufferDecoder::next_raw_block`].
+    #[inline]
+    pub fn decode<const CHANNELS: usize, R: std::io::Read>(
+        &self,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<(usize, usize), Error> {
+        let symbol = self.types.decode(buffer)?;
+        let code = *blocks::Typ::<CHANNELS>::CODES
+            .get(symbol)
+            .ok_or(Error::InvalidData("unknown type of block"))?;
+        let payload = match &self.fields[symbol] {
+            Some(table) => {
+                let (bits, count) = blocks::Typ::<CHANNELS>::fields(code);
+                let mut payload = 0;
+                for _ in 0..count {
+                    payload = (payload << bits) | table.decode(buffer)?;
+                }
+                payload
+            }
+            None => buffer.next_n_bits(blocks::Typ::<CHANNELS>::block_len(code))?,
+        };
+        Ok((code, payload))
+    }
+
+    /// Write the lengths of the codes, the codes of the fields of the types that never appear are
+    /// skipped.
+    pub fn write(&self, buffer: &mut buffer::Buffer) {
+        self.types.write(buffer);
+        self.fields
+            .iter()
+            .flatten()
+            .for_each(|code| code.write(buffer));
+    }
+
+    pub fn read<const CHANNELS: usize, R: std::io::Read>(
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<Self, Error> {
+        let codes = blocks::Typ::<CHANNELS>::CODES;
+        let types = Code::read(codes.len(), buffer)?;
+        let mut fields = Vec::with_capacity(codes.len());
+        for (symbol, code) in codes.iter().enumerate() {
+            let (bits, _) = blocks::Typ::<CHANNELS>::fields(*code);
+            fields.push(if types.lengths[symbol] > 0 && bits <= MAX_FIELD_BITS {
+                Some(Code::read(1 << bits, buffer)?)
+            } else {
+                None
+            });
+        }
+        Ok(Self { types, fields })
+    }
+
+    /// Return the lengths of every code in the order they are written, for the checksum of the
+    /// header.
+    pub fn lengths(&self) -> Vec<u8> {
+        let fields = self.fields.iter().flatten();
+        let lengths = std::iter::once(&self.types).chain(fields);
+        lengths
+            .flat_map(|code| code.lengths.iter().copied())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn code_from_counts() {
+        // The most frequent symbols get the shortest codes and the codes form a prefix code.
+        let code = Code::from_counts(&[50, 0, 25, 20, 5, 4]);
+        assert_eq!(code.lengths(), [1, 0, 2, 3, 4, 4]);
+        let single = Code::from_counts(&[0, 0, 7]);
+        assert_eq!(single.lengths(), [0, 0, 1]);
+        // Fibonacci counts give the deepest trees, the codes are limited anyway.
+        let mut counts = vec![1, 1];
+        while counts.len() < 40 {
+            counts.push(counts[counts.len() - 1] + counts[counts.len() - 2]);
+        }
+        let code = Code::from_counts(&counts);
+        assert_eq!(code.lengths().iter().max(), Some(&(MAX_CODE_LEN as u8)));
+    }
+
+    #[test]
+    fn code_from_lengths() {
+        assert_eq!(
+            Code::from_lengths(vec![1, 1, 2]),
+            Err(Error::InvalidData("the Huffman code is not a prefix code"))
+        );
+    }
+}
@@ -0,0 +1,124 @@
+/// Everything that can go wrong while encoding or decoding an image. New variants can be added
+/// by any release.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Error {
+    /// The width or the height is 0 or the image is too big to be addressed in memory.
+    InvalidDimensions { width: u32, height: u32 },
+    /// The raw buffer does not contain exactly `width * height * channels` bytes.
+    InvalidBufferLength { expected: usize, actual: usize },
+    /// The number of channels is not supported by the encoder.
+    UnsupportedChannels(usize),
+    /// The stream does not start with the boi signature.
+    InvalidMagic,
+    /// The stream was produced by a version of the format that is not supported.
+    UnsupportedVersion(u8),
+    /// The stream ended before all the pixels of the image were decoded.
+    Truncated,
+    /// The stream encoder was finished before all the rows of the image were written.
+    MissingRows { expected: u32, actual: u32 },
+    /// The stream contains something that cannot be part of a valid image.
+    InvalidData(&'static str),
+    /// The checksum stored in the stream does not match the decoded header or pixels, the stream
+    /// is corrupted.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// Reading or writing the stream failed. The error of the reader or the writer is shared so
+    /// that the error can be cloned, it is the source of this one.
+    Io(std::sync::Arc<std::io::Error>),
+}
+
+/// Two i/o errors are equal when they are of the same kind.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Self::InvalidDimensions { width, height },
+                Self::InvalidDimensions {
+                    width: other_width,
+                    height: other_height,
+                },
+            ) => width == other_width && height == other_height,
+            (
+                Self::InvalidBufferLength { expected, actual },
+                Self::InvalidBufferLength {
+                    expected: other_expected,
+                    actual: other_actual,
+                },
+            ) => expected == other_expected && actual == other_actual,
+            (Self::UnsupportedChannels(channels), Self::UnsupportedChannels(other)) => {
+                channels == other
+            }
+            (Self::InvalidMagic, Self::InvalidMagic) | (Self::Truncated, Self::Truncated) => true,
+            (Self::UnsupportedVersion(version), Self::UnsupportedVersion(other)) => {
+                version == other
+            }
+            (Self::InvalidData(reason), Self::InvalidData(other)) => reason == other,
+            (
+                Self::MissingRows { expected, actual },
+                Self::MissingRows {
+                    expected: other_expected,
+                    actual: other_actual,
+                },
+            )
+            | (
+                Self::ChecksumMismatch { expected, actual },
+                Self::ChecksumMismatch {
+                    expected: other_expected,
+                    actual: other_actual,
+                },
+            ) => expected == other_expected && actual == other_actual,
+            (Self::Io(error), Self::Io(other)) => error.kind() == other.kind(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidDimensions { width, height } => {
+                write!(f, "invalid image dimensions: {width}x{height}")
+            }
+            Self::InvalidBufferLength { expected, actual } => write!(
+                f,
+                "invalid buffer length: expected {expected} bytes, got {actual} bytes"
+            ),
+            Self::UnsupportedChannels(channels) => {
+                write!(f, "unsupported number of channels: {channels}")
+            }
+            Self::InvalidMagic => write!(f, "the stream is not a boi image"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported version of the format: {version}")
+            }
+            Self::Truncated => write!(f, "the stream ended before the end of the image"),
+            Self::MissingRows { expected, actual } => write!(
+                f,
+                "missing rows: {} of the {expected} rows of the image were not written",
+                expected - actual
+            ),
+            Self::InvalidData(reason) => write!(f, "invalid data: {reason}"),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {expected:#010x}, got {actual:#010x}"
+            ),
+            Self::Io(error) => write!(f, "i/o error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(std::sync::Arc::new(error))
+    }
+}
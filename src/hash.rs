@@ -0,0 +1,100 @@
+use crate::img;
+use crate::Error;
+
+/// A hash function of the values of the pixels, used to find their slot in the table of the
+/// offsets. A good hash spreads the values that appear together in an image over different slots
+/// so that fewer of them are evicted before they are seen again.
+pub trait PixelHash {
+    /// Return the hash of `pixel`. Only its remainder by the size of the table is used.
+    fn hash<const CHANNELS: usize>(pixel: &img::Pixel<CHANNELS>) -> usize;
+}
+
+/// The hash of qoi: the channels are multiplied by small primes and summed.
+pub struct Qoi;
+
+impl PixelHash for Qoi {
+    #[inline]
+    fn hash<const CHANNELS: usize>(pixel: &img::Pixel<CHANNELS>) -> usize {
+        pixel.hash()
+    }
+}
+
+/// FNV-1a over the two bytes of each channel.
+pub struct Fnv;
+
+impl Fnv {
+    const OFFSET_BASIS: u32 = 0x811c_9dc5;
+    const PRIME: u32 = 0x0100_0193;
+}
+
+impl PixelHash for Fnv {
+    #[inline]
+    fn hash<const CHANNELS: usize>(pixel: &img::Pixel<CHANNELS>) -> usize {
+        let hash = pixel
+            .0
+            .iter()
+            .flat_map(|channel| channel.to_le_bytes())
+            .fold(Self::OFFSET_BASIS, |hash, byte| {
+                (hash ^ byte as u32).wrapping_mul(Self::PRIME)
+            });
+        // The low bits of FNV are the weakest, fold the high ones into them.
+        (hash ^ (hash >> 16)) as usize
+    }
+}
+
+/// The channels are packed in 64 bits and mixed with the finalizer of MurmurHash3. It is the
+/// slowest and the most uniform of the hashes.
+pub struct Murmur;
+
+impl PixelHash for Murmur {
+    #[inline]
+    fn hash<const CHANNELS: usize>(pixel: &img::Pixel<CHANNELS>) -> usize {
+        let mut hash = pixel
+            .0
+            .iter()
+            .fold(0u64, |hash, channel| (hash << 16) | *channel as u16 as u64);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^= hash >> 33;
+        hash as usize
+    }
+}
+
+/// The hash functions that can be stored in the header, see [`PixelHash`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HashFunction {
+    /// See [`Qoi`].
+    #[default]
+    Qoi,
+    /// See [`Fnv`].
+    Fnv,
+    /// See [`Murmur`].
+    Murmur,
+}
+
+impl TryFrom<usize> for HashFunction {
+    type Error = Error;
+
+    fn try_from(value: usize) -> Result<Self, Error> {
+        match value {
+            0 => Ok(Self::Qoi),
+            1 => Ok(Self::Fnv),
+            2 => Ok(Self::Murmur),
+            _ => Err(Error::InvalidData("unknown hash function")),
+        }
+    }
+}
+
+impl HashFunction {
+    /// Return the hash of `pixel`.
+    #[inline]
+    pub fn hash<const CHANNELS: usize>(self, pixel: &img::Pixel<CHANNELS>) -> usize {
+        match self {
+            Self::Qoi => Qoi::hash(pixel),
+            Self::Fnv => Fnv::hash(pixel),
+            Self::Murmur => Murmur::hash(pixel),
+        }
+    }
+}
@@ -1,7 +1,121 @@
 use crate::blocks;
 use crate::buffer;
+use crate::checksum;
+use crate::entropy;
+use crate::hash;
 use crate::img;
+use crate::metadata;
 use crate::palette;
+use crate::predictor;
+use crate::Error;
+
+/// A sample of a channel of the raw image. Samples of 16 bits are stored in big endian, like in
+/// png.
+pub trait Sample: Copy + Default + 'static {
+    /// Number of bytes of a sample in the raw image.
+    const BYTES: usize;
+
+    fn read(bytes: &[u8]) -> Self;
+
+    fn write(self, bytes: &mut [u8]);
+
+    /// Return the delta between 2 samples, `previous - current`.
+    fn delta(previous: Self, current: Self) -> i16;
+
+    /// Return the current sample from the previous one and the delta returned by
+    /// [`Sample::delta`].
+    fn undo_delta(previous: Self, delta: i16) -> Self;
+
+    fn to_i32(self) -> i32;
+
+    /// Return the sample from a value returned by a [`predictor::Predictor`], it is always in the
+    /// range of the samples.
+    fn from_i32(value: i32) -> Self;
+}
+
+impl Sample for u8 {
+    const BYTES: usize = 1;
+
+    #[inline]
+    fn read(bytes: &[u8]) -> Self {
+        bytes[0]
+    }
+
+    #[inline]
+    fn write(self, bytes: &mut [u8]) {
+        bytes[0] = self;
+    }
+
+    #[inline]
+    fn delta(previous: Self, current: Self) -> i16 {
+        previous as i16 - current as i16
+    }
+
+    #[inline]
+    fn undo_delta(previous: Self, delta: i16) -> Self {
+        // A corrupted stream can contain any delta.
+        (previous as i16).wrapping_sub(delta) as u8
+    }
+
+    #[inline]
+    fn to_i32(self) -> i32 {
+        self as i32
+    }
+
+    #[inline]
+    fn from_i32(value: i32) -> Self {
+        value as u8
+    }
+}
+
+/// The delta of 16 bits samples wraps around so that it always fits in an `i16`.
+impl Sample for u16 {
+    const BYTES: usize = 2;
+
+    #[inline]
+    fn read(bytes: &[u8]) -> Self {
+        u16::from_be_bytes([bytes[0], bytes[1]])
+    }
+
+    #[inline]
+    fn write(self, bytes: &mut [u8]) {
+        bytes[..2].copy_from_slice(&self.to_be_bytes());
+    }
+
+    #[inline]
+    fn delta(previous: Self, current: Self) -> i16 {
+        previous.wrapping_sub(current) as i16
+    }
+
+    #[inline]
+    fn undo_delta(previous: Self, delta: i16) -> Self {
+        previous.wrapping_sub(delta as u16)
+    }
+
+    #[inline]
+    fn to_i32(self) -> i32 {
+        self as i32
+    }
+
+    #[inline]
+    fn from_i32(value: i32) -> Self {
+        value as u16
+    }
+}
+
+/// Read the samples of a pixel from the raw bytes of the image.
+#[inline]
+pub fn read_samples<S: Sample, const CHANNELS: usize>(bytes: &[u8]) -> [S; CHANNELS] {
+    std::array::from_fn(|idx| S::read(&bytes[idx * S::BYTES..]))
+}
+
+/// Write the samples of a pixel to the raw bytes of the image.
+#[inline]
+pub fn write_samples<S: Sample>(samples: &[S], bytes: &mut [u8]) {
+    for (sample, bytes) in samples.iter().zip(bytes.chunks_exact_mut(S::BYTES)) {
+        sample.write(bytes);
+    }
+}
 
 /// An `Pixel` is computed from the previous and current pixel of the actual image. An
 /// `Pixel` is actually the preivous minus pixel value minus the current pixel value.
@@ -9,6 +123,10 @@ use crate::palette;
 pub struct Pixel<const CHANNELS: usize>(pub [i16; CHANNELS]);
 
 impl<const CHANNELS: usize> Pixel<CHANNELS> {
+    /// Number of channels holding the color, the following channel (if any) is the alpha
+    /// channel. Gray images (L and LA) have a single color channel.
+    pub const COLOR_CHANNELS: usize = if CHANNELS >= 3 { 3 } else { 1 };
+
     #[inline]
     pub fn zeros() -> Self {
         Self([0; CHANNELS])
@@ -16,38 +134,34 @@ impl<const CHANNELS: usize> Pixel<CHANNELS> {
 
     /// Compute the value of a pixel that is going to be encoded.
     #[inline]
-    pub fn compute_forward(previous: &[u8], current: &[u8]) -> Self {
+    pub fn compute_forward<S: Sample>(previous: &[S], current: &[S]) -> Self {
         let mut pixel = [0; CHANNELS];
         for idx in 0..pixel.len() {
-            pixel[idx] = previous[idx] as i16 - current[idx] as i16;
+            pixel[idx] = S::delta(previous[idx], current[idx]);
         }
         Self(pixel)
     }
 
-    /// Compute the value of a pixel that is being decoded
+    /// Compute the value of a pixel that is being decoded and write it to `pixel`.
     #[inline]
-    pub fn compute_backward(previous: &[u8], current: &Self) -> Vec<u8> {
-        let mut pixel = vec![0; CHANNELS];
-        for idx in 0..pixel.len() {
-            pixel[idx] = (previous[idx] as i16 - current.0[idx]) as u8;
+    pub fn compute_backward<S: Sample>(previous: &[S], current: &Self, pixel: &mut [S]) {
+        for idx in 0..CHANNELS {
+            pixel[idx] = S::undo_delta(previous[idx], current.0[idx]);
         }
-        pixel
     }
 
-    /// Return a hash for the color of the pixel. This is used to compute the offset. This uses the
-    /// exact same function as qoi uses for the hash algorithm. Some research maybe needed to find
-    /// a better or faster one.
-    ///
-    /// SPIKE: A slower algorithm could be used for better compression depending on the options
-    /// choosen.
+    /// Return a hash for the color of the pixel. This uses the exact same function as qoi uses for
+    /// the hash algorithm, without the remainder. It places the colors of the palettes, the offsets
+    /// can use other hashes, see [`hash::HashFunction`].
     #[inline]
     pub fn hash(&self) -> usize {
-        let mut hash = 0;
         let test = [3, 5, 7, 11];
-        for idx in 0..self.0.len() {
-            hash += self.0[idx] as usize * test[idx];
-        }
-        hash % blocks::Offset::MAX
+        self.0
+            .iter()
+            .zip(test)
+            .fold(0usize, |hash, (channel, prime)| {
+                hash.wrapping_add((*channel as usize).wrapping_mul(prime))
+            })
     }
 
     /// Return a vec of the underlying array
@@ -64,9 +178,14 @@ pub struct Image<const CHANNELS: usize> {
 }
 
 impl<const CHANNELS: usize> Image<CHANNELS> {
-    pub fn new(raw: &[u8], width: usize, height: usize) -> Self {
-        let image_palette = palette::ImagePalette::from(raw);
-        let palette = palette::Palette::from(image_palette);
+    /// Create the image from the value of its pixels.
+    pub fn new(
+        pixels: &[Pixel<CHANNELS>],
+        width: usize,
+        height: usize,
+        effort: palette::PaletteEffort,
+    ) -> Self {
+        let palette = palette::Palette::with_effort(pixels, effort);
         Self {
             width,
             height,
@@ -75,54 +194,394 @@ impl<const CHANNELS: usize> Image<CHANNELS> {
     }
 }
 
+/// Signature at the start of every boi file.
+pub const MAGIC: [u8; 4] = *b"boif";
+
+/// Version of the bitstream. It must be incremented every time the layout of the file or the
+/// blocks in [`blocks::Typ`] change.
+pub const VERSION: u8 = 14;
+
+/// The oldest version that can still be decoded. The blocks have not changed since, the later
+/// versions only added fields at the end of the fixed part of the header, see
+/// [`ImageInfo::size_of_version`], and values to the entropy flags.
+pub const MIN_VERSION: u8 = 10;
+
+/// The colorspace of the image. It is only informative, the samples are stored as is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Colorspace {
+    /// sRGB color channels with a linear alpha channel.
+    #[default]
+    Srgb,
+    /// All the channels are linear.
+    Linear,
+}
+
+/// The number of bits of each sample.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    /// The samples are stored in big endian in the raw image.
+    Sixteen,
+}
+
+impl BitDepth {
+    /// Number of bytes of a sample in the raw image.
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Eight => 1,
+            Self::Sixteen => 2,
+        }
+    }
+}
+
+impl TryFrom<usize> for Colorspace {
+    type Error = Error;
+
+    fn try_from(value: usize) -> Result<Self, Error> {
+        match value {
+            0 => Ok(Self::Srgb),
+            1 => Ok(Self::Linear),
+            _ => Err(Error::InvalidData("unknown colorspace")),
+        }
+    }
+}
+
+/// Describe the raw pixels of an image. The pixels are stored row by row with the channels
+/// interleaved. It is built with [`ImageInfo::new`] and the `with_` methods, new fields can be
+/// added by any release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub channels: usize,
+    pub colorspace: Colorspace,
+    pub bit_depth: BitDepth,
+    /// How the pixels are predicted from their neighbors before being encoded.
+    pub predictor: predictor::Predictor,
+    /// The size of the square tiles with their own palette, a power of two between
+    /// [`ImageInfo::MIN_TILE_SIZE`] and [`ImageInfo::MAX_TILE_SIZE`]. With 0, the image has a
+    /// single palette stored in the header.
+    pub tile_size: u32,
+    /// The hash function of the table of the offsets.
+    pub hash: hash::HashFunction,
+    /// The number of pixels of the cache of the offsets, a power of two between
+    /// [`blocks::Offset::MAX`] and [`blocks::Offset::MAX_CACHE`].
+    pub offsets: u32,
+    /// The number of pixels of each set of the cache of the offsets, a power of two up to
+    /// [`blocks::Offset::MAX_WAYS`]. See [`crate::offsets::Offsets`].
+    pub offset_ways: u32,
+    /// How the blocks are written after the header.
+    pub entropy: entropy::Entropy,
+}
+
+impl ImageInfo {
+    /// Number of bytes of the fixed part of the header.
+    pub(crate) const SIZE: usize = 21;
+    /// Flag set in the header when the samples have 16 bits.
+    pub(crate) const FLAG_16_BITS: usize = 0b1;
+    /// The bits of the flags storing the entropy coding.
+    pub(crate) const FLAGS_ENTROPY: usize = 0b110;
+    pub const MIN_TILE_SIZE: u32 = 1 << 4;
+    pub const MAX_TILE_SIZE: u32 = 1 << 15;
+
+    pub fn new(width: u32, height: u32, channels: usize) -> Self {
+        Self {
+            width,
+            height,
+            channels,
+            colorspace: Colorspace::default(),
+            bit_depth: BitDepth::default(),
+            predictor: predictor::Predictor::default(),
+            tile_size: 0,
+            hash: hash::HashFunction::default(),
+            offsets: blocks::Offset::MAX as u32,
+            offset_ways: 1,
+            entropy: entropy::Entropy::default(),
+        }
+    }
+
+    pub fn with_colorspace(mut self, colorspace: Colorspace) -> Self {
+        self.colorspace = colorspace;
+        self
+    }
+
+    pub fn with_bit_depth(mut self, bit_depth: BitDepth) -> Self {
+        self.bit_depth = bit_depth;
+        self
+    }
+
+    pub fn with_predictor(mut self, predictor: predictor::Predictor) -> Self {
+        self.predictor = predictor;
+        self
+    }
+
+    pub fn with_tile_size(mut self, tile_size: u32) -> Self {
+        self.tile_size = tile_size;
+        self
+    }
+
+    pub fn with_hash(mut self, hash: hash::HashFunction) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub fn with_offsets(mut self, offsets: u32, ways: u32) -> Self {
+        self.offsets = offsets;
+        self.offset_ways = ways;
+        self
+    }
+
+    pub fn with_entropy(mut self, entropy: entropy::Entropy) -> Self {
+        self.entropy = entropy;
+        self
+    }
+
+    /// Return the number of bytes of the fixed part of the header of `version`. The hash function
+    /// was added in version 11 and the cache of the offsets in version 12, the older files use the
+    /// defaults of [`ImageInfo::new`].
+    pub(crate) fn size_of_version(version: u8) -> usize {
+        match version {
+            ..=10 => 18,
+            11 => 19,
+            _ => Self::SIZE,
+        }
+    }
+
+    /// Return the flags stored in the header.
+    pub(crate) fn flags(&self) -> usize {
+        let bit_depth = match self.bit_depth {
+            BitDepth::Eight => 0,
+            BitDepth::Sixteen => Self::FLAG_16_BITS,
+        };
+        bit_depth | ((self.entropy as usize) << Self::FLAGS_ENTROPY.trailing_zeros())
+    }
+
+    /// Return the fixed part of the header: the magic, the version, the channels, the colorspace,
+    /// the flags, the width, the height, the predictor, the log2 of the size of the tiles, the
+    /// hash function and the log2 of the size and of the ways of the cache of the offsets.
+    pub(crate) fn to_bytes(self) -> [u8; Self::SIZE] {
+        self.header_bytes(VERSION)
+    }
+
+    /// Return the fixed part of the header as written by `version`, only its first
+    /// [`ImageInfo::size_of_version`] bytes are part of the header.
+    fn header_bytes(&self, version: u8) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4] = version;
+        bytes[5] = self.channels as u8;
+        bytes[6] = self.colorspace as u8;
+        bytes[7] = self.flags() as u8;
+        bytes[8..12].copy_from_slice(&self.width.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.height.to_be_bytes());
+        bytes[16] = self.predictor as u8;
+        bytes[17] = self.tile_size.checked_ilog2().unwrap_or(0) as u8;
+        bytes[18] = self.hash as u8;
+        bytes[19] = self.offsets.ilog2() as u8;
+        bytes[20] = self.offset_ways.ilog2() as u8;
+        bytes
+    }
+
+    /// Return the number of bytes of a row of the raw image.
+    pub fn row_len(&self) -> usize {
+        self.width as usize * self.channels * self.bit_depth.bytes()
+    }
+
+    /// Return the number of bytes needed to store the raw pixels of the image. Fails if the
+    /// number of channels is not supported or if the dimensions are invalid.
+    pub fn raw_len(&self) -> Result<usize, Error> {
+        if !(1..=4).contains(&self.channels) {
+            return Err(Error::UnsupportedChannels(self.channels));
+        }
+        (self.width as usize)
+            .checked_mul(self.height as usize)
+            .filter(|count| *count > 0)
+            .and_then(|count| count.checked_mul(self.channels * self.bit_depth.bytes()))
+            .ok_or(Error::InvalidDimensions {
+                width: self.width,
+                height: self.height,
+            })
+    }
+
+    /// Check that the image can be encoded: the number of channels, the dimensions, the size of
+    /// the tiles and of the cache of the offsets must be valid.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.raw_len()?;
+        let tile_sizes = Self::MIN_TILE_SIZE..=Self::MAX_TILE_SIZE;
+        if self.tile_size != 0
+            && !(self.tile_size.is_power_of_two() && tile_sizes.contains(&self.tile_size))
+        {
+            return Err(Error::InvalidData("invalid size of the tiles"));
+        }
+        let offsets = blocks::Offset::MAX as u32..=blocks::Offset::MAX_CACHE as u32;
+        if !(self.offsets.is_power_of_two() && offsets.contains(&self.offsets)) {
+            return Err(Error::InvalidData("invalid size of the offsets"));
+        }
+        let ways = 1..=blocks::Offset::MAX_WAYS as u32;
+        if !(self.offset_ways.is_power_of_two() && ways.contains(&self.offset_ways)) {
+            return Err(Error::InvalidData("invalid number of ways of the offsets"));
+        }
+        Ok(())
+    }
+
+    /// Return the number of pixels of the image.
+    pub fn pixels_count(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+impl ImageInfo {
+    /// Read the fixed part of the header and return it with the version of the file. The fields
+    /// missing from the older versions keep their default value.
+    pub(crate) fn decode<R: std::io::Read>(
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<(Self, u8), Error> {
+        let mut bytes = Self::new(0, 0, 0).to_bytes();
+        for byte in bytes[..5].iter_mut() {
+            *byte = buffer.next_n_bits(8)? as u8;
+        }
+        if bytes[..4] != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = bytes[4];
+        if !(MIN_VERSION..=VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        for byte in bytes[5..Self::size_of_version(version)].iter_mut() {
+            *byte = buffer.next_n_bits(8)? as u8;
+        }
+        let channels = bytes[5] as usize;
+        let colorspace = Colorspace::try_from(bytes[6] as usize)?;
+        let flags = bytes[7] as usize;
+        if flags & !(Self::FLAG_16_BITS | Self::FLAGS_ENTROPY) != 0 {
+            return Err(Error::InvalidData("unknown flags in the header"));
+        }
+        let bit_depth = if flags & Self::FLAG_16_BITS != 0 {
+            BitDepth::Sixteen
+        } else {
+            BitDepth::Eight
+        };
+        let entropy = flags & Self::FLAGS_ENTROPY;
+        let entropy = entropy::Entropy::try_from(entropy >> Self::FLAGS_ENTROPY.trailing_zeros())?;
+        let width = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+        let height = u32::from_be_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+        let predictor = predictor::Predictor::try_from(bytes[16] as usize)?;
+        let tile_size = match bytes[17] {
+            0 => 0,
+            log2 @ 1..=31 => 1 << log2,
+            _ => return Err(Error::InvalidData("invalid size of the tiles")),
+        };
+        let hash = hash::HashFunction::try_from(bytes[18] as usize)?;
+        // Invalid sizes are rejected by `validate`, 0 is never a valid size.
+        let offsets = 1u32.checked_shl(bytes[19] as u32).unwrap_or(0);
+        let offset_ways = 1u32.checked_shl(bytes[20] as u32).unwrap_or(0);
+        let info = Self {
+            width,
+            height,
+            channels,
+            colorspace,
+            bit_depth,
+            predictor,
+            tile_size,
+            hash,
+            offsets,
+            offset_ways,
+            entropy,
+        };
+        info.validate()?;
+        Ok((info, version))
+    }
+}
+
 /// An image header containing informations about the image to be decoded.
 #[derive(Debug)]
 pub struct Header<const CHANNELS: usize> {
-    /// With of the image
-    pub width: u32,
-    /// Height of the image
-    pub height: u32,
+    /// The description of the image
+    pub info: ImageInfo,
+    /// The optional data stored between the fixed part of the header and the palette.
+    pub metadata: metadata::Metadata,
     /// The color palette used in the image of the most present colors.
     pub encoded_palette: Vec<blocks::Block>,
     /// The color palette
     pub palette: Vec<img::Pixel<CHANNELS>>,
+    /// The Huffman codes of the blocks when the entropy coding is [`entropy::Entropy::Huffman`].
+    pub tables: Option<entropy::Tables>,
+    /// The checksum of the fixed part of the header, of the palette and of the Huffman codes.
+    pub checksum: u32,
 }
 
 impl<const CHANNELS: usize> Header<CHANNELS> {
-    pub fn new(width: usize, height: usize, palette: &palette::Palette<CHANNELS>) -> Self {
+    pub fn new(
+        info: ImageInfo,
+        metadata: metadata::Metadata,
+        palette: &palette::Palette<CHANNELS>,
+        tables: Option<entropy::Tables>,
+    ) -> Self {
         let encoded_palette = palette
-            .0
+            .colors()
             .iter()
-            .map(|color| blocks::Pixel::encode(color))
+            .map(blocks::Pixel::encode)
             .collect::<Vec<_>>();
         Self {
-            width: width as u32,
-            height: height as u32,
+            info,
+            metadata,
             encoded_palette,
             palette: Vec::new(),
+            checksum: Self::checksum(&info, VERSION, palette.colors(), tables.as_ref()),
+            tables,
         }
     }
-}
 
-impl<const CHANNELS: usize> From<&mut buffer::BufferDecoder> for Header<CHANNELS> {
-    fn from(buffer: &mut buffer::BufferDecoder) -> Self {
-        let _is_alpha = buffer.next_n_bits(1).unwrap() == 1;
-        let width = buffer.next_n_bits(32).unwrap();
-        let height = buffer.next_n_bits(32).unwrap();
-        let palette = (0..blocks::Color::MAX)
-            .map(|idx| {
-                if let Some((code, encoded_value)) = buffer.next_block::<CHANNELS>() {
-                    blocks::Pixel::<CHANNELS>::decode(encoded_value, code)
-                } else {
-                    unreachable!();
-                }
-            })
-            .collect::<Vec<_>>();
-        Self {
-            width: width as u32,
-            height: height as u32,
+    /// Compute the checksum of the header, the channels of the palette are added in big endian and
+    /// the lengths of the Huffman codes one per byte.
+    fn checksum(
+        info: &ImageInfo,
+        version: u8,
+        palette: &[img::Pixel<CHANNELS>],
+        tables: Option<&entropy::Tables>,
+    ) -> u32 {
+        let mut crc = checksum::Crc32::new();
+        let bytes = info.header_bytes(version);
+        crc.update(&bytes[..ImageInfo::size_of_version(version)]);
+        crc.update(&[palette.len() as u8]);
+        for pixel in palette {
+            for channel in pixel.0 {
+                crc.update(&channel.to_be_bytes());
+            }
+        }
+        if let Some(tables) = tables {
+            crc.update(&tables.lengths());
+        }
+        crc.value()
+    }
+
+    /// Read the metadata, the palette and the Huffman codes following the fixed part of the header
+    /// of `version` and check the checksum of the header.
+    pub fn decode<R: std::io::Read>(
+        info: ImageInfo,
+        version: u8,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<Self, Error> {
+        let metadata = metadata::Metadata::decode(buffer)?;
+        let palette = palette::Palette::<CHANNELS>::decode(buffer)?;
+        let tables = match info.entropy {
+            entropy::Entropy::Raw | entropy::Entropy::Rans => None,
+            entropy::Entropy::Huffman => Some(entropy::Tables::read::<CHANNELS, R>(buffer)?),
+        };
+        let expected = buffer.next_n_bits(u32::BITS as usize)? as u32;
+        let actual = Self::checksum(&info, version, &palette, tables.as_ref());
+        if expected != actual {
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+        Ok(Self {
+            info,
+            metadata,
             encoded_palette: Vec::new(),
             palette,
-        }
+            tables,
+            checksum: actual,
+        })
     }
 }
@@ -1,13 +1,34 @@
 #![allow(dead_code)]
 mod blocks;
 mod buffer;
+mod checksum;
 mod decoder;
 mod encoder;
+mod entropy;
+mod error;
+mod hash;
 mod img;
+mod metadata;
+mod offsets;
 mod palette;
+mod predictor;
+mod rans;
 mod squares;
 mod tests;
 
+pub use decoder::{decode, DecodedImage, StreamDecoder};
+pub use encoder::{
+    encode, encode_image, encode_with_metadata, encode_with_options, Effort, EncoderOptions,
+    StreamEncoder,
+};
+pub use entropy::Entropy;
+pub use error::Error;
+pub use hash::HashFunction;
+pub use img::{BitDepth, Colorspace, ImageInfo};
+pub use metadata::Metadata;
+pub use palette::PaletteEffort;
+pub use predictor::Predictor;
+
 pub const U8_BITS: usize = u8::BITS as usize;
 pub const USIZE_BITS: usize = usize::BITS as usize;
 
@@ -15,21 +36,50 @@ pub const USIZE_BITS: usize = usize::BITS as usize;
 mod test {
     use super::*;
 
-    fn open_image(path: String) -> (Vec<u8>, usize, usize, bool) {
-        let decoder = png::Decoder::new(std::fs::File::open(path.clone()).unwrap());
+    const CORPUS: [&str; 4] = [
+        "./img/wallpaper",
+        "./img/kodak",
+        "./img/screenshots",
+        "./img/textures",
+    ];
+
+    /// Return the path of every image in the corpus. The corpus is not part of the repository,
+    /// the tests using it are ignored by default and run with `cargo test -- --ignored`. Some of
+    /// the directories may be missing, but the corpus must not be empty.
+    fn corpus() -> Vec<String> {
+        let paths: Vec<_> = CORPUS
+            .iter()
+            .filter_map(|dir| std::fs::read_dir(dir).ok())
+            .flat_map(|paths| paths.map(|path| path.unwrap().path().display().to_string()))
+            .collect();
+        assert!(!paths.is_empty(), "no image in {CORPUS:?}");
+        paths
+    }
+
+    /// Open a png image and return its raw bytes and its description. Indexed images are
+    /// expanded to RGB(A), 16 bits samples are kept as is.
+    fn open_image(path: String) -> (Vec<u8>, ImageInfo) {
+        let mut decoder = png::Decoder::new(std::fs::File::open(path).unwrap());
+        decoder.set_transformations(png::Transformations::EXPAND);
         let mut reader = decoder.read_info().unwrap();
         let mut buf = vec![0; reader.output_buffer_size()];
         let next_frame = reader.next_frame(&mut buf).unwrap();
         let bytes = buf[..next_frame.buffer_size()].to_vec();
+        let (color_type, bit_depth) = reader.output_color_type();
         let info = reader.info();
-        let is_alpha = info.color_type == png::ColorType::Rgba;
-        (bytes, info.width as usize, info.height as usize, is_alpha)
+        let bit_depth = match bit_depth {
+            png::BitDepth::Sixteen => BitDepth::Sixteen,
+            _ => BitDepth::Eight,
+        };
+        let info =
+            ImageInfo::new(info.width, info.height, color_type.samples()).with_bit_depth(bit_depth);
+        (bytes, info)
     }
 
     fn save_image(path: &str, width: u32, height: u32, bytes: &[u8]) {
         let path = std::path::Path::new(path);
         let file = std::fs::File::create(path).unwrap();
-        let ref mut w = std::io::BufWriter::new(file);
+        let w = &mut std::io::BufWriter::new(file);
 
         let mut encoder = png::Encoder::new(w, width, height);
         match true {
@@ -42,51 +92,879 @@ mod test {
         writer.write_image_data(bytes).unwrap();
     }
 
+    /// Return `len` pseudo random samples, always the same for a given `seed`.
+    fn random_pixels(mut seed: u32, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Encode `raw` a row at a time with the stream encoder.
+    fn stream_encode(info: ImageInfo, raw: &[u8]) -> Vec<u8> {
+        stream_encode_with_options(info, &Metadata::new(), &EncoderOptions::default(), raw)
+    }
+
+    fn stream_encode_with_options(
+        info: ImageInfo,
+        metadata: &Metadata,
+        options: &EncoderOptions,
+        raw: &[u8],
+    ) -> Vec<u8> {
+        let mut encoder = StreamEncoder::with_options(Vec::new(), info, metadata, options).unwrap();
+        for row in raw.chunks_exact(info.row_len()) {
+            encoder.write_row(row).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    /// Generate an image with a gradient, flat areas, repeated colors and noise so that every
+    /// kind of block ends up in the stream.
+    fn synthetic_image(width: usize, height: usize, channels: usize) -> Vec<u8> {
+        let random = random_pixels(0x2545_f491, width * height);
+        let mut bytes = Vec::with_capacity(width * height * channels);
+        for y in 0..height {
+            for x in 0..width {
+                let noise = random[y * width + x];
+                let pixel = match y * 4 / height {
+                    0 => [
+                        (x * 4) as u8,
+                        (y * 5) as u8,
+                        ((x + y) * 2) as u8,
+                        (x * 3) as u8,
+                    ],
+                    1 if x < width / 2 => [200, 30, 30, 255],
+                    1 => [12, 180, 90, 128],
+                    2 => [[0, 0, 0, 255], [255, 255, 255, 0]][(x / 3 + y) % 2],
+                    _ => [
+                        noise,
+                        noise.wrapping_mul(3),
+                        noise ^ 0x5a,
+                        noise.rotate_left(3),
+                    ],
+                };
+                bytes.extend_from_slice(&pixel[..channels]);
+            }
+        }
+        bytes
+    }
+
     #[test]
+    #[ignore = "needs the ./img corpus"]
     fn test_encoder() {
         let mut tests = tests::TestImages::new();
-        for dir in [
-            "./img/wallpaper",
-            "./img/kodak",
-            "./img/screenshots",
-            "./img/textures",
-        ] {
-            let paths = std::fs::read_dir(dir).unwrap();
-            for path in paths {
-                let path = path.unwrap().path().display().to_string();
-                let test = tests::TestImage::test(path);
-                println!("{}", test);
-                tests.add(test);
-            }
+        for path in corpus() {
+            let test = tests::TestImage::test(path);
+            println!("{}", test);
+            tests.add(test);
         }
         tests.agregator.average();
         println!("{}", tests);
-        assert!(true);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (width, height) = (67, 41);
+        let raw = synthetic_image(width, height, 3);
+        let encoded = encode(&raw, width as u32, height as u32, 3).unwrap();
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded.info, ImageInfo::new(width as u32, height as u32, 3));
+        assert_eq!(decoded.pixels, raw);
+    }
+
+    #[test]
+    fn test_header() {
+        let raw = synthetic_image(8, 8, 3);
+        let info = ImageInfo::new(8, 8, 3).with_colorspace(Colorspace::Linear);
+        let mut encoded = encode_image(&raw, &info).unwrap();
+        assert_eq!(&encoded[..4], b"boif");
+        assert_eq!(decode(&encoded).unwrap().info, info);
+
+        encoded[4] = img::VERSION + 1;
+        assert_eq!(
+            decode(&encoded),
+            Err(Error::UnsupportedVersion(img::VERSION + 1))
+        );
+        encoded[4] = img::MIN_VERSION - 1;
+        assert_eq!(
+            decode(&encoded),
+            Err(Error::UnsupportedVersion(img::MIN_VERSION - 1))
+        );
+        encoded[0] = b'p';
+        assert_eq!(decode(&encoded), Err(Error::InvalidMagic));
+    }
+
+    #[test]
+    fn test_old_versions() {
+        // Encoded by the previous versions of the crate, the older ones have no hash function and
+        // no cache of the offsets in their header.
+        let files: [&[u8]; 4] = [
+            include_bytes!("tests/fixtures/v10.boi"),
+            include_bytes!("tests/fixtures/v11.boi"),
+            include_bytes!("tests/fixtures/v12.boi"),
+            include_bytes!("tests/fixtures/v13.boi"),
+        ];
+        let info = ImageInfo::new(40, 24, 3)
+            .with_predictor(Predictor::Adaptive)
+            .with_tile_size(16);
+        for (version, file) in (img::MIN_VERSION..).zip(files) {
+            assert_eq!(file[4], version);
+            let decoded = decode(file).unwrap();
+            assert_eq!(decoded.info, info);
+            assert_eq!(decoded.pixels, synthetic_image(40, 24, 3));
+        }
+    }
+
+    #[test]
+    fn test_round_trip_alpha() {
+        let (width, height) = (53, 38);
+        let raw = synthetic_image(width, height, 4);
+        let encoded = encode(&raw, width as u32, height as u32, 4).unwrap();
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded.info, ImageInfo::new(width as u32, height as u32, 4));
+        assert_eq!(decoded.pixels, raw);
+    }
+
+    #[test]
+    fn test_round_trip_gray() {
+        let (width, height) = (41, 27);
+        for channels in [1, 2] {
+            let raw = synthetic_image(width, height, channels);
+            let encoded = encode(&raw, width as u32, height as u32, channels).unwrap();
+            let decoded = decode(&encoded).unwrap();
+            assert_eq!(decoded.info.channels, channels);
+            assert_eq!(decoded.pixels, raw);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_16_bits() {
+        let (width, height) = (37, 29);
+        for channels in 1..=4 {
+            // Spread the 8 bits samples over 16 bits with some noise in the low byte so that
+            // every pixel block size is used, including the `Wide` ones.
+            let raw = synthetic_image(width, height, channels)
+                .into_iter()
+                .enumerate()
+                .flat_map(|(idx, sample)| {
+                    let low = (idx as u16).wrapping_mul(2_654_435_761u32 as u16) % 7;
+                    (((sample as u16) << 8) | low).to_be_bytes()
+                })
+                .collect::<Vec<_>>();
+            let info = ImageInfo::new(width as u32, height as u32, channels)
+                .with_bit_depth(BitDepth::Sixteen);
+            let encoded = encode_image(&raw, &info).unwrap();
+            assert_eq!(encoded[7], 0b1);
+            let decoded = decode(&encoded).unwrap();
+            assert_eq!(decoded.info, info);
+            assert_eq!(decoded.pixels, raw);
+
+            assert_eq!(decode(&stream_encode(info, &raw)).unwrap().pixels, raw);
+        }
+    }
+
+    #[test]
+    fn test_stream_encoder() {
+        for channels in 1..=4 {
+            let (width, height) = (45, 31);
+            let raw = synthetic_image(width, height, channels);
+            let info = ImageInfo::new(width as u32, height as u32, channels);
+            assert_eq!(decode(&stream_encode(info, &raw)).unwrap().pixels, raw);
+        }
+    }
+
+    #[test]
+    fn test_stream_encoder_errors() {
+        let info = ImageInfo::new(4, 2, 3);
+        let mut encoder = StreamEncoder::new(Vec::new(), info).unwrap();
+        assert_eq!(
+            encoder.write_row(&[0; 9]),
+            Err(Error::InvalidBufferLength {
+                expected: 12,
+                actual: 9
+            })
+        );
+        encoder.write_row(&[0; 12]).unwrap();
+        let error = encoder.finish().err().unwrap();
+        assert_eq!(
+            error,
+            Error::MissingRows {
+                expected: 2,
+                actual: 1
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "missing rows: 1 of the 2 rows of the image were not written"
+        );
+    }
+
+    /// A writer failing once it has accepted its number of bytes, like a full disk.
+    struct FullWriter(usize);
+
+    impl std::io::Write for FullWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match self.0 {
+                0 => Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "no space left",
+                )),
+                _ => {
+                    let len = buf.len().min(self.0);
+                    self.0 -= len;
+                    Ok(len)
+                }
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_io_errors() {
+        let info = ImageInfo::new(4, 2, 3);
+        let error = StreamEncoder::new(FullWriter(10), info).err().unwrap();
+        let io_error = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        assert_eq!(error, Error::from(io_error));
+        assert_eq!(error.to_string(), "i/o error: no space left");
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(source.to_string(), "no space left");
+    }
+
+    /// A reader returning at most 3 bytes at a time, like a slow socket.
+    struct SlowReader<'a>(&'a [u8]);
+
+    impl std::io::Read for SlowReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(3);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_stream_decoder() {
+        let (width, height) = (29, 23);
+        let raw = synthetic_image(width, height, 4);
+        let encoded = encode(&raw, width as u32, height as u32, 4).unwrap();
+        let decoder = StreamDecoder::new(SlowReader(&encoded)).unwrap();
+        assert_eq!(
+            *decoder.info(),
+            ImageInfo::new(width as u32, height as u32, 4)
+        );
+        let rows = decoder.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows.len(), height);
+        assert_eq!(rows.concat(), raw);
+
+        let mut decoder = StreamDecoder::new(&encoded[..encoded.len() / 2]).unwrap();
+        assert_eq!(decoder.find_map(Result::err), Some(Error::Truncated));
+        assert!(decoder.next().is_none());
+    }
+
+    #[test]
+    #[ignore = "needs the ./img corpus"]
+    fn test_round_trip_corpus() {
+        for path in corpus() {
+            let (bytes, info) = open_image(path.clone());
+            let encoded = encode_image(&bytes, &info).unwrap();
+            assert_eq!(decode(&encoded).unwrap().pixels, bytes, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let raw = synthetic_image(4, 4, 3);
+        assert_eq!(
+            encode(&raw, 0, 4, 3),
+            Err(Error::InvalidDimensions {
+                width: 0,
+                height: 4
+            })
+        );
+        assert_eq!(
+            encode(&raw, 4, 3, 3),
+            Err(Error::InvalidBufferLength {
+                expected: 36,
+                actual: 48
+            })
+        );
+        assert_eq!(encode(&raw, 4, 4, 5), Err(Error::UnsupportedChannels(5)));
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let raw = synthetic_image(16, 16, 3);
+        let encoded = encode(&raw, 16, 16, 3).unwrap();
+        assert_eq!(decode(&[]), Err(Error::Truncated));
+        for len in [1, 8, encoded.len() / 2, encoded.len() - 1] {
+            assert_eq!(decode(&encoded[..len]), Err(Error::Truncated));
+        }
+
+        // A valid header of a huge image does not allocate the whole image upfront.
+        let mut header = Vec::new();
+        let info = ImageInfo::new(60_000, 60_000, 4);
+        let mut encoder = StreamEncoder::new(&mut header, info).unwrap();
+        encoder.write_row(&vec![0; info.row_len()]).unwrap();
+        drop(encoder);
+        assert_eq!(decode(&header), Err(Error::Truncated));
+        let mut decoder = StreamDecoder::new(header.as_slice()).unwrap();
+        assert_eq!(*decoder.info(), info);
+        assert!(decoder.any(|row| row == Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn test_observer() {
+        let raw = synthetic_image(31, 17, 4);
+        let info = ImageInfo::new(31, 17, 4);
+        let mut logger = tests::log::Logger::new();
+        let encoded = encoder::encode_with_observer(
+            &raw,
+            &info,
+            &Metadata::default(),
+            &EncoderOptions::default(),
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(encoded, encode_image(&raw, &info).unwrap());
+        assert!(logger.repeating > 0 && logger.short + logger.medium + logger.long > 0);
+        assert_eq!(decode(&encoded).unwrap().pixels, raw);
+    }
+
+    #[test]
+    fn test_blobs() {
+        // Flat rectangles wider than a blob on a noisy background. The last one touches the right
+        // and the bottom edges of the image.
+        let (width, height) = (300, 90);
+        let rectangles = [(10, 5, 120, 30), (150, 20, 80, 50), (200, 60, 100, 30)];
+        for channels in 1..=4 {
+            let mut raw = synthetic_image(width, height, channels);
+            for (idx, (x, y, w, h)) in rectangles.into_iter().enumerate() {
+                for row in y..y + h {
+                    let start = (row * width + x) * channels;
+                    raw[start..start + w * channels].fill(40 * idx as u8 + 7);
+                }
+            }
+            let info = ImageInfo::new(width as u32, height as u32, channels);
+            let mut logger = tests::log::Logger::new();
+            let encoded = encoder::encode_with_observer(
+                &raw,
+                &info,
+                &Metadata::default(),
+                &EncoderOptions::default(),
+                &mut logger,
+            )
+            .unwrap();
+            assert!(logger.blob >= rectangles.len(), "{channels} channels");
+            assert_eq!(decode(&encoded).unwrap().pixels, raw, "{channels} channels");
+
+            // The fastest effort disables the blobs and the regions.
+            let mut logger = tests::log::Logger::new();
+            let encoded = encoder::encode_with_observer(
+                &raw,
+                &info,
+                &Metadata::default(),
+                &EncoderOptions::from_effort(Effort::Fastest),
+                &mut logger,
+            )
+            .unwrap();
+            assert_eq!(logger.blob + logger.region, 0, "{channels} channels");
+            assert_eq!(decode(&encoded).unwrap().pixels, raw, "{channels} channels");
+        }
+    }
+
+    #[test]
+    fn test_regions() {
+        // Flat frames narrower than a blob around a noisy area, plus a flat rectangle.
+        let (width, height) = (160, 90);
+        for channels in 1..=4 {
+            let mut raw = synthetic_image(width, height, channels);
+            for (x, y, side) in [(5, 5, 50), (90, 20, 60)] {
+                for row in y..y + side {
+                    for col in x..x + side {
+                        let is_border = row < y + 8 || row >= y + side - 8 || col < x + 8;
+                        if is_border || col >= x + side - 8 {
+                            let start = (row * width + col) * channels;
+                            raw[start..start + channels].fill(201);
+                        }
+                    }
+                }
+            }
+            for row in 70..90 {
+                let start = (row * width + 10) * channels;
+                raw[start..start + 100 * channels].fill(17);
+            }
+            let info = ImageInfo::new(width as u32, height as u32, channels);
+            let mut logger = tests::log::Logger::new();
+            let encoded = encoder::encode_with_observer(
+                &raw,
+                &info,
+                &Metadata::default(),
+                &EncoderOptions::default(),
+                &mut logger,
+            )
+            .unwrap();
+            assert!(logger.region >= 2, "{channels} channels");
+            assert!(logger.blob >= 1, "{channels} channels");
+            assert_eq!(decode(&encoded).unwrap().pixels, raw, "{channels} channels");
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        let raw = synthetic_image(19, 13, 3);
+        let info = ImageInfo::new(19, 13, 3);
+        let metadata = Metadata::new()
+            .with_icc_profile(vec![7; 300])
+            .with_exif(b"Exif\0\0MM".to_vec())
+            .with_text("Author", "Xavier")
+            .with_text("Source", "https://example.com/é");
+        let encoded = encode_with_metadata(&raw, &info, &metadata).unwrap();
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded.metadata, metadata);
+        assert_eq!(decoded.pixels, raw);
+        assert_eq!(
+            decode(&encode_image(&raw, &info).unwrap())
+                .unwrap()
+                .metadata,
+            Metadata::new()
+        );
+
+        let options = EncoderOptions::default();
+        let encoded = stream_encode_with_options(info, &metadata, &options, &raw);
+        let decoder = StreamDecoder::new(&encoded[..]).unwrap();
+        assert_eq!(decoder.metadata(), &metadata);
+
+        // Chunks with an unknown tag are skipped.
+        let mut chunk = b"zzzz".to_vec();
+        chunk.extend_from_slice(&3u32.to_be_bytes());
+        chunk.extend_from_slice(b"abc");
+        chunk.extend_from_slice(&checksum::Crc32::compute(b"zzzzabc").to_be_bytes());
+        let mut unknown = encoded.clone();
+        unknown.splice(ImageInfo::SIZE..ImageInfo::SIZE, chunk);
+        assert_eq!(decode(&unknown).unwrap().metadata, metadata);
+
+        let invalid = Metadata::new().with_text("", "value");
+        assert!(matches!(
+            encode_with_metadata(&raw, &info, &invalid),
+            Err(Error::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn test_checksum() {
+        let raw = synthetic_image(16, 16, 3);
+        let encoded = encode(&raw, 16, 16, 3).unwrap();
+        let mut corrupted = encoded.clone();
+        corrupted[11] ^= 0b1;
+        assert!(matches!(
+            decode(&corrupted),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+        // Some flipped bits are harmless (an offset to another slot with the same pixel, the
+        // padding bits...), the decoder must either fail or return the original pixels.
+        for idx in ImageInfo::SIZE..encoded.len() {
+            for bit in 0..8 {
+                let mut corrupted = encoded.clone();
+                corrupted[idx] ^= 1 << bit;
+                if let Ok(decoded) = decode(&corrupted) {
+                    assert_eq!(decoded.pixels, raw, "byte {idx}, bit {bit}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_predictors() {
+        let predictors = [
+            Predictor::Left,
+            Predictor::Up,
+            Predictor::Average,
+            Predictor::Paeth,
+            Predictor::Med,
+            Predictor::Adaptive,
+        ];
+        let (width, height) = (29, 23);
+        for channels in 1..=4 {
+            let raw = synthetic_image(width, height, channels);
+            let raw_16_bits = raw
+                .iter()
+                .flat_map(|sample| (*sample as u16 * 257).to_be_bytes())
+                .collect::<Vec<_>>();
+            for predictor in predictors {
+                for (raw, bit_depth) in [(&raw, BitDepth::Eight), (&raw_16_bits, BitDepth::Sixteen)]
+                {
+                    let info = ImageInfo::new(width as u32, height as u32, channels)
+                        .with_bit_depth(bit_depth)
+                        .with_predictor(predictor);
+                    let encoded = encode_image(raw, &info).unwrap();
+                    let decoded = decode(&encoded).unwrap();
+                    assert_eq!(decoded.info.predictor, predictor);
+                    assert_eq!(&decoded.pixels, raw);
+
+                    assert_eq!(&decode(&stream_encode(info, raw)).unwrap().pixels, raw);
+                }
+            }
+        }
+
+        // Vertical stripes of random colors are only predictable from the row above.
+        let (width, height) = (64, 64);
+        let stripes = random_pixels(0x9e37_79b9, width * 3);
+        let raw = (0..height)
+            .flat_map(|y| {
+                stripes
+                    .iter()
+                    .map(move |sample| sample.wrapping_add(y as u8))
+            })
+            .collect::<Vec<_>>();
+        let len = |predictor| {
+            let info = ImageInfo::new(width as u32, height as u32, 3).with_predictor(predictor);
+            encode_image(&raw, &info).unwrap().len()
+        };
+        let left = len(Predictor::Left);
+        for predictor in [Predictor::Up, Predictor::Paeth, Predictor::Med] {
+            assert!(len(predictor) < left, "{predictor:?}");
+        }
+
+        let mut encoded = encode(&raw, width as u32, height as u32, 3).unwrap();
+        encoded[16] = 6;
+        assert_eq!(
+            decode(&encoded),
+            Err(Error::InvalidData("unknown predictor"))
+        );
+    }
+
+    #[test]
+    fn test_adaptive_predictor() {
+        // Vertical stripes on the top half, predictable from the row above, and horizontal
+        // gradients with a different slope on each row on the bottom half, predictable from the
+        // pixel on the left.
+        let (width, height) = (64, 64);
+        let mut random = random_pixels(0x1234_5678, width * 3 + height).into_iter();
+        let stripes = random.by_ref().take(width * 3).collect::<Vec<_>>();
+        let mut raw = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            if y < height / 2 {
+                raw.extend(stripes.iter().map(|sample| sample.wrapping_add(y as u8)));
+            } else {
+                let (start, slope) = (random.next().unwrap(), random.next().unwrap() % 8);
+                raw.extend(
+                    (0..width * 3)
+                        .map(|idx| start.wrapping_add(slope.wrapping_mul((idx / 3) as u8))),
+                );
+            }
+        }
+        let len = |predictor| {
+            let info = ImageInfo::new(width as u32, height as u32, 3).with_predictor(predictor);
+            let encoded = encode_image(&raw, &info).unwrap();
+            assert_eq!(decode(&encoded).unwrap().pixels, raw);
+            encoded.len()
+        };
+        let adaptive = len(Predictor::Adaptive);
+        for predictor in [Predictor::Left, Predictor::Up, Predictor::Paeth] {
+            assert!(adaptive < len(predictor), "{predictor:?}");
+        }
+    }
+
+    #[test]
+    fn test_tile_palettes() {
+        // Each quarter of the image is made of its own 4 colors, their deltas do not fit in a
+        // single palette.
+        let (width, height) = (128, 128);
+        let random = random_pixels(0x0bad_cafe, 16 * 3 + width * height);
+        let (colors, choices) = random.split_at(16 * 3);
+        let mut raw = Vec::with_capacity(width * height * 3);
+        for (idx, choice) in choices.iter().enumerate() {
+            let quarter = idx / width / 64 * 2 + idx % width / 64;
+            let color = quarter * 4 + *choice as usize % 4;
+            raw.extend_from_slice(&colors[color * 3..color * 3 + 3]);
+        }
+        let info = ImageInfo::new(width as u32, height as u32, 3);
+        let single = encode_image(&raw, &info).unwrap();
+        for tile_size in [16, 64, 256] {
+            let info = info.with_tile_size(tile_size);
+            let encoded = encode_image(&raw, &info).unwrap();
+            let decoded = decode(&encoded).unwrap();
+            assert_eq!(decoded.info, info);
+            assert_eq!(decoded.pixels, raw);
+
+            assert_eq!(decode(&stream_encode(info, &raw)).unwrap().pixels, raw);
+        }
+        let tiled = encode_image(&raw, &info.with_tile_size(64)).unwrap();
+        assert!(tiled.len() < single.len());
+
+        let adaptive = info.with_tile_size(32).with_predictor(Predictor::Adaptive);
+        let decoded = decode(&encode_image(&raw, &adaptive).unwrap()).unwrap();
+        assert_eq!(decoded.pixels, raw);
+
+        for tile_size in [8, 48, 1 << 16] {
+            assert_eq!(
+                encode_image(&raw, &info.with_tile_size(tile_size)),
+                Err(Error::InvalidData("invalid size of the tiles"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_palette_effort() {
+        // A small image made of a few colors, too small for the sampled palette to see them all.
+        let (width, height) = (32, 32);
+        let random = random_pixels(0x5eed_1234, 5 * 3 + width * height);
+        let (colors, choices) = random.split_at(5 * 3);
+        let raw = choices
+            .iter()
+            .flat_map(|choice| {
+                let color = *choice as usize % 5;
+                colors[color * 3..color * 3 + 3].iter().copied()
+            })
+            .collect::<Vec<_>>();
+        let info = ImageInfo::new(width as u32, height as u32, 3);
+        let metadata = Metadata::new();
+        let len = |info: &ImageInfo, level| {
+            let options =
+                EncoderOptions::new().with_palette_effort(PaletteEffort::from_level(level));
+            let encoded = encode_with_options(&raw, info, &metadata, &options).unwrap();
+            assert_eq!(decode(&encoded).unwrap().pixels, raw);
+            encoded.len()
+        };
+        let sampled = len(&info, 0);
+        for level in 1..=9 {
+            assert!(len(&info, level) < sampled, "level {level}");
+        }
+        assert!(len(&info.with_tile_size(16), 3) <= len(&info.with_tile_size(16), 0));
+    }
+
+    #[test]
+    fn test_encoder_options() {
+        // Flat areas for the blobs and the regions next to a noisy gradient for the predictors.
+        let (width, height) = (96, 64);
+        let raw = random_pixels(0xfeed_5eed, width * height)
+            .into_iter()
+            .enumerate()
+            .flat_map(|(idx, random)| {
+                let (x, y) = (idx % width, idx / width);
+                let noise = random >> 4;
+                match (x < 80, (x / 8 + y / 8) % 3) {
+                    (true, 0) => [200, 30, 30],
+                    (true, 1) => [30, 200, 30],
+                    (true, _) => [x as u8, y as u8, 90],
+                    (false, _) => [(x + y) as u8 + noise, (2 * y) as u8, x as u8 ^ noise],
+                }
+            })
+            .collect::<Vec<_>>();
+        let info = ImageInfo::new(width as u32, height as u32, 3);
+        let metadata = Metadata::new();
+        let efforts = [
+            Effort::Fastest,
+            Effort::Fast,
+            Effort::Default,
+            Effort::Small,
+            Effort::Smallest,
+        ];
+        let lens = efforts.map(|effort| {
+            let options = EncoderOptions::from_effort(effort);
+            let encoded = encode_with_options(&raw, &info, &metadata, &options).unwrap();
+            let image = decode(&encoded).unwrap();
+            assert_eq!(image.pixels, raw, "{effort:?}");
+            let predictor = options.predictor.unwrap_or(info.predictor);
+            assert_eq!(image.info.predictor, predictor, "{effort:?}");
+            encoded.len()
+        });
+        // More effort never makes the image larger.
+        assert!(lens.windows(2).all(|pair| pair[1] <= pair[0]), "{lens:?}");
+        assert_eq!(
+            EncoderOptions::default(),
+            EncoderOptions::from_effort(Effort::Default)
+        );
+
+        // The stream encoder uses the predictor of the options too.
+        let options = EncoderOptions::from_effort(Effort::Smallest);
+        let encoded = stream_encode_with_options(info, &metadata, &options, &raw);
+        let image = decode(&encoded).unwrap();
+        assert_eq!(image.pixels, raw);
+        assert_eq!(image.info.predictor, Predictor::Adaptive);
+        // The entropy coding of the options is not supported by the stream encoder, unlike the
+        // one of the image info it is not an error.
+        assert_eq!(options.entropy, Some(Entropy::Rans));
+        assert_eq!(image.info.entropy, Entropy::Raw);
+        let info = info.with_entropy(Entropy::Rans);
+        assert!(StreamEncoder::with_options(Vec::new(), info, &metadata, &options).is_err());
+    }
+
+    #[test]
+    fn test_hash_functions() {
+        let (width, height) = (61, 47);
+        let raw = synthetic_image(width, height, 4);
+        for hash in [HashFunction::Qoi, HashFunction::Fnv, HashFunction::Murmur] {
+            let info = ImageInfo::new(width as u32, height as u32, 4).with_hash(hash);
+            let encoded = encode_image(&raw, &info).unwrap();
+            assert_eq!(encoded[18], hash as u8);
+            let decoded = decode(&encoded).unwrap();
+            assert_eq!(decoded.info, info);
+            assert_eq!(decoded.pixels, raw);
+        }
+        // The hash of qoi only uses a quarter of the slots for these pixels.
+        let slots = |hash: HashFunction| {
+            let pixels = (0..1000).map(|idx| img::Pixel([idx, -idx, 2 * idx, 7]));
+            let slots = pixels.map(|pixel| hash.hash(&pixel) % 256);
+            slots.collect::<std::collections::HashSet<_>>().len()
+        };
+        assert_eq!(slots(HashFunction::Qoi), 64);
+        assert!(slots(HashFunction::Fnv) > 200);
+        assert!(slots(HashFunction::Murmur) > 200);
+        let options = EncoderOptions::new().with_hash(HashFunction::Murmur);
+        let info = ImageInfo::new(width as u32, height as u32, 4);
+        let mut encoded = encode_with_options(&raw, &info, &Metadata::new(), &options).unwrap();
+        assert_eq!(decode(&encoded).unwrap().info.hash, HashFunction::Murmur);
+        encoded[18] = 3;
+        assert_eq!(
+            decode(&encoded).err(),
+            Some(Error::InvalidData("unknown hash function"))
+        );
+    }
+
+    #[test]
+    fn test_offset_cache() {
+        // Many colors seen again after a while, so that they collide in the cache.
+        let (width, height) = (80, 60);
+        let colors = random_pixels(0xcafe_f00d, 400 * 4);
+        let raw = (0..width * height)
+            .flat_map(|idx| {
+                let color = (idx / 3 + idx % 7 * 50) % 400;
+                colors[color * 4..color * 4 + 4].iter().copied()
+            })
+            .collect::<Vec<_>>();
+        let metadata = Metadata::new();
+        let mut hit_rates = Vec::new();
+        for (len, ways) in [(256, 1), (256, 8), (1024, 2), (4096, 4)] {
+            let info = ImageInfo::new(width as u32, height as u32, 4).with_offsets(len, ways);
+            let mut logger = tests::log::Logger::new();
+            let options = EncoderOptions::default();
+            let encoded =
+                encoder::encode_with_observer(&raw, &info, &metadata, &options, &mut logger)
+                    .unwrap();
+            assert_eq!(encoded[19..21], [len.ilog2() as u8, ways.ilog2() as u8]);
+            let decoded = decode(&encoded).unwrap();
+            assert_eq!(decoded.info, info);
+            assert_eq!(decoded.pixels, raw);
+            assert_eq!(logger.offset_hits, logger.offset);
+            hit_rates.push(logger.offset_hit_rate());
+        }
+        // The colors do not fit in the smallest caches, they all fit in the largest one.
+        assert!(
+            hit_rates[3] > hit_rates[0].max(hit_rates[1]),
+            "{hit_rates:?}"
+        );
+
+        let info = ImageInfo::new(width as u32, height as u32, 4);
+        for (len, ways) in [(128, 1), (8192, 1), (300, 1), (256, 16), (256, 3)] {
+            assert!(info.with_offsets(len, ways).validate().is_err());
+        }
+        let mut encoded = encode_image(&raw, &info).unwrap();
+        encoded[19] = 40;
+        assert_eq!(
+            decode(&encoded).err(),
+            Some(Error::InvalidData("invalid size of the offsets"))
+        );
+    }
+
+    #[test]
+    fn test_entropy() {
+        // Skewed blocks, the entropy codings make them smaller than the raw blocks.
+        let (width, height) = (128, 96);
+        let skewed = (0..width * height)
+            .flat_map(|idx| [(idx % 7 * 3) as u8, (idx % 5) as u8, 40, 255])
+            .collect::<Vec<_>>();
+        let skewed_info = ImageInfo::new(width as u32, height as u32, 4);
+        let raw_len = encode_image(&skewed, &skewed_info).unwrap().len();
+        let mut lens = Vec::new();
+        for (entropy, flags) in [(Entropy::Huffman, 0b010), (Entropy::Rans, 0b100)] {
+            for channels in 1..=4 {
+                let (width, height) = (59, 43);
+                let raw = synthetic_image(width, height, channels);
+                let info =
+                    ImageInfo::new(width as u32, height as u32, channels).with_entropy(entropy);
+                let encoded = encode_image(&raw, &info).unwrap();
+                assert_eq!(encoded[7], flags);
+                let decoded = decode(&encoded).unwrap();
+                assert_eq!(decoded.info, info);
+                assert_eq!(decoded.pixels, raw);
+
+                // The palettes of the tiles, the tags of the rows and the large offsets are coded
+                // too.
+                let info = info
+                    .with_bit_depth(BitDepth::Sixteen)
+                    .with_predictor(Predictor::Adaptive)
+                    .with_tile_size(16)
+                    .with_offsets(4096, 8);
+                let raw = raw
+                    .iter()
+                    .flat_map(|sample| (*sample as u16 * 3).to_be_bytes())
+                    .collect::<Vec<_>>();
+                let encoded = encode_image(&raw, &info).unwrap();
+                let decoder = StreamDecoder::new(SlowReader(&encoded)).unwrap();
+                assert_eq!(*decoder.info(), info);
+                let rows = decoder.collect::<Result<Vec<_>, _>>().unwrap();
+                assert_eq!(rows.concat(), raw);
+            }
+
+            let info = skewed_info.with_entropy(entropy);
+            let encoded = encode_image(&skewed, &info).unwrap();
+            assert!(encoded.len() < raw_len);
+            assert_eq!(decode(&encoded).unwrap().pixels, skewed);
+            lens.push(encoded.len());
+
+            let len = encoded.len();
+            let mut corrupted = encoded.clone();
+            corrupted[len - 8] ^= 0b1010;
+            assert!(decode(&corrupted).is_err());
+            assert!(decode(&encoded[..len - 2]).is_err());
+            // The stream encoder does not support the entropy codings.
+            assert!(StreamEncoder::new(Vec::new(), info).is_err());
+        }
+        // The adaptive models beat the static Huffman codes.
+        assert!(lens[1] < lens[0]);
+
+        let mut corrupted = encode_image(&skewed, &skewed_info).unwrap();
+        corrupted[7] = 0b110;
+        assert_eq!(
+            decode(&corrupted),
+            Err(Error::InvalidData("unknown entropy coding"))
+        );
     }
 
     //#[test]
     fn test_encoder_single() {
         let path = "./img/screenshots/en.wikipedia.org.png".to_string();
         //let path = "./img/wallpaper/1492858.png".to_string();
-        let (bytes, width, height, is_alpha) = open_image(path);
-        let (_, encoded) = if !is_alpha {
-            encoder::Encoder::<3>::encode_with_logger(&bytes, width, height)
-        } else {
-            encoder::Encoder::<4>::encode_with_logger(&bytes, width, height)
-        };
-        save_image("./img/out.png", width as u32, height as u32, &encoded);
+        let (bytes, info) = open_image(path);
+        let mut logger = tests::log::Logger::new();
+        let encoded = encoder::encode_with_observer(
+            &bytes,
+            &info,
+            &Metadata::default(),
+            &EncoderOptions::default(),
+            &mut logger,
+        )
+        .unwrap();
+        save_image("./img/out.png", info.width, info.height, &encoded);
     }
 
     //#[test]
     fn test_decoder() {
         let path = "./img/wallpaper/1492858.png".to_string();
-        let (bytes, width, height, is_alpha) = open_image(path);
-        let encoded = if !is_alpha {
-            encoder::Encoder::<3>::encode(&bytes, width, height)
-        } else {
-            encoder::Encoder::<4>::encode(&bytes, width, height)
-        };
-        let (boi_bytes, width, height) = decoder::decode(encoded);
-        save_image("./img/out.png", width, height, &boi_bytes);
+        let (bytes, info) = open_image(path);
+        let encoded = encode_image(&bytes, &info).unwrap();
+        let image = decode(&encoded).unwrap();
+        save_image(
+            "./img/out.png",
+            image.info.width,
+            image.info.height,
+            &image.pixels,
+        );
     }
 }
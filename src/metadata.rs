@@ -0,0 +1,172 @@
+use crate::blocks;
+use crate::buffer;
+use crate::checksum;
+use crate::Error;
+use crate::U8_BITS;
+
+/// Optional data stored with the image. It does not change how the pixels are decoded.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// An embedded ICC color profile.
+    pub icc_profile: Option<Vec<u8>>,
+    /// A raw EXIF blob.
+    pub exif: Option<Vec<u8>>,
+    /// UTF-8 key/value pairs such as the author or the source of the image, in the order they
+    /// are stored.
+    pub text: Vec<(String, String)>,
+}
+
+/// The metadata is stored as a list of chunks following the fixed part of the header. A chunk is
+/// made of a tag, the length of the data as a big endian `u32`, the data and the CRC32 of the tag
+/// and the data. The list ends with the [`Chunk::END`] tag alone. Decoders skip the chunks with an
+/// unknown tag.
+struct Chunk;
+
+impl Chunk {
+    const ICC_PROFILE: [u8; 4] = *b"iccp";
+    const EXIF: [u8; 4] = *b"exif";
+    const TEXT: [u8; 4] = *b"text";
+    const END: [u8; 4] = *b"iend";
+
+    /// Separates the key from the value in a [`Chunk::TEXT`].
+    const TEXT_SEPARATOR: u8 = 0;
+
+    fn encode(buffer: &mut buffer::Buffer, tag: [u8; 4], data: &[&[u8]]) {
+        let mut crc = checksum::Crc32::new();
+        crc.update(&tag);
+        Self::push_bytes(buffer, &tag);
+        let len = data.iter().map(|data| data.len()).sum::<usize>();
+        buffer.push(blocks::Block::new(u32::BITS as usize, len));
+        for data in data {
+            crc.update(data);
+            Self::push_bytes(buffer, data);
+        }
+        buffer.push_checksum(crc.value());
+    }
+
+    fn push_bytes(buffer: &mut buffer::Buffer, bytes: &[u8]) {
+        for byte in bytes {
+            buffer.push(blocks::Block::new(U8_BITS, *byte as usize));
+        }
+    }
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_icc_profile(mut self, icc_profile: Vec<u8>) -> Self {
+        self.icc_profile = Some(icc_profile);
+        self
+    }
+
+    pub fn with_exif(mut self, exif: Vec<u8>) -> Self {
+        self.exif = Some(exif);
+        self
+    }
+
+    pub fn with_text(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.text.push((key.into(), value.into()));
+        self
+    }
+
+    /// Check that the metadata can be stored. The keys of the text must not be empty nor contain
+    /// a nul character and every chunk must be smaller than 4GiB.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (key, _) in self.text.iter() {
+            if key.is_empty() || key.as_bytes().contains(&Chunk::TEXT_SEPARATOR) {
+                return Err(Error::InvalidData("invalid key in the text metadata"));
+            }
+        }
+        let too_long = self
+            .icc_profile
+            .iter()
+            .chain(self.exif.iter())
+            .map(|data| data.len())
+            .chain(
+                self.text
+                    .iter()
+                    .map(|(key, value)| key.len() + value.len() + 1),
+            )
+            .any(|len| len > u32::MAX as usize);
+        if too_long {
+            return Err(Error::InvalidData("the metadata is too long"));
+        }
+        Ok(())
+    }
+
+    /// Push the chunks of the metadata. The metadata must have been validated.
+    pub fn encode(&self, buffer: &mut buffer::Buffer) {
+        if let Some(icc_profile) = &self.icc_profile {
+            Chunk::encode(buffer, Chunk::ICC_PROFILE, &[icc_profile]);
+        }
+        if let Some(exif) = &self.exif {
+            Chunk::encode(buffer, Chunk::EXIF, &[exif]);
+        }
+        for (key, value) in self.text.iter() {
+            let data = [key.as_bytes(), &[Chunk::TEXT_SEPARATOR], value.as_bytes()];
+            Chunk::encode(buffer, Chunk::TEXT, &data);
+        }
+        Chunk::push_bytes(buffer, &Chunk::END);
+    }
+
+    /// Read the chunks of the metadata up to the [`Chunk::END`] tag.
+    pub fn decode<R: std::io::Read>(buffer: &mut buffer::BufferDecoder<R>) -> Result<Self, Error> {
+        let mut metadata = Self::default();
+        loop {
+            let tag = Self::next_bytes(buffer, 4)?;
+            if tag == Chunk::END {
+                return Ok(metadata);
+            }
+            let len = buffer.next_n_bits(u32::BITS as usize)?;
+            let data = Self::next_bytes(buffer, len)?;
+            let expected = buffer.next_n_bits(u32::BITS as usize)? as u32;
+            let mut crc = checksum::Crc32::new();
+            crc.update(&tag);
+            crc.update(&data);
+            let actual = crc.value();
+            if expected != actual {
+                return Err(Error::ChecksumMismatch { expected, actual });
+            }
+            match [tag[0], tag[1], tag[2], tag[3]] {
+                Chunk::ICC_PROFILE if metadata.icc_profile.is_none() => {
+                    metadata.icc_profile = Some(data)
+                }
+                Chunk::EXIF if metadata.exif.is_none() => metadata.exif = Some(data),
+                Chunk::ICC_PROFILE | Chunk::EXIF => {
+                    return Err(Error::InvalidData("duplicated metadata chunk"))
+                }
+                Chunk::TEXT => metadata.text.push(Self::decode_text(data)?),
+                _ => {}
+            }
+        }
+    }
+
+    fn decode_text(mut data: Vec<u8>) -> Result<(String, String), Error> {
+        let separator = data
+            .iter()
+            .position(|byte| *byte == Chunk::TEXT_SEPARATOR)
+            .filter(|separator| *separator > 0)
+            .ok_or(Error::InvalidData("invalid key in the text metadata"))?;
+        let value = data.split_off(separator + 1);
+        data.truncate(separator);
+        match (String::from_utf8(data), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => Ok((key, value)),
+            _ => Err(Error::InvalidData("the text metadata is not valid UTF-8")),
+        }
+    }
+
+    /// Read `count` bytes. The bytes are read one by one so that a corrupted length does not
+    /// allocate more than what the stream contains.
+    fn next_bytes<R: std::io::Read>(
+        buffer: &mut buffer::BufferDecoder<R>,
+        count: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        for _ in 0..count {
+            bytes.push(buffer.next_n_bits(U8_BITS)? as u8);
+        }
+        Ok(bytes)
+    }
+}
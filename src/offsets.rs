@@ -0,0 +1,72 @@
+use crate::img;
+
+/// The pixels seen recently, referenced by the `Offset` blocks. The cache is made of sets of
+/// `ways` pixels, a pixel can only be stored in the set of its hash. The pixels of a set are
+/// ordered from the most to the least recently used and the least recently used one is evicted
+/// when a new pixel is stored. With a single way, the cache is a table indexed by the hash where
+/// every pixel overwrites the previous one of its slot.
+///
+/// The encoder and the decoder push the same pixels in the same order, the index of a pixel in
+/// the cache is therefore the same on both sides.
+pub struct Offsets<const CHANNELS: usize> {
+    ways: usize,
+    pixels: Vec<img::Pixel<CHANNELS>>,
+}
+
+impl<const CHANNELS: usize> Offsets<CHANNELS> {
+    /// Create a cache of `len` pixels in sets of `ways` pixels, both are powers of two.
+    pub fn new(len: usize, ways: usize) -> Self {
+        Self {
+            ways,
+            pixels: vec![img::Pixel::zeros(); len],
+        }
+    }
+
+    /// Return the pixel at `idx`.
+    #[inline]
+    pub fn get(&self, idx: usize) -> img::Pixel<CHANNELS> {
+        self.pixels[idx]
+    }
+
+    /// Record `pixel` as the most recently used pixel of the set of `hash` and return the index it
+    /// had before, if it was in the cache.
+    #[inline]
+    pub fn push(&mut self, pixel: img::Pixel<CHANNELS>, hash: usize) -> Option<usize> {
+        let sets = self.pixels.len() / self.ways;
+        let start = (hash % sets) * self.ways;
+        let set = &mut self.pixels[start..start + self.ways];
+        match set.iter().position(|cached| *cached == pixel) {
+            Some(way) => {
+                set[..=way].rotate_right(1);
+                Some(start + way)
+            }
+            None => {
+                set.rotate_right(1);
+                set[0] = pixel;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn least_recently_used() {
+        // The least recently used pixel of a set is evicted, a hit moves the pixel to the front.
+        let mut cache = Offsets::<3>::new(256, 4);
+        let pixels = (1..=5)
+            .map(|red| img::Pixel([red, 0, 0]))
+            .collect::<Vec<_>>();
+        for pixel in &pixels[..4] {
+            assert_eq!(cache.push(*pixel, 7), None);
+        }
+        assert_eq!(cache.push(pixels[0], 7), Some(7 * 4 + 3));
+        assert_eq!(cache.get(7 * 4), pixels[0]);
+        assert_eq!(cache.push(pixels[4], 7), None);
+        assert_eq!(cache.push(pixels[1], 7), None);
+        assert_eq!(cache.push(pixels[0], 7), Some(7 * 4 + 2));
+    }
+}
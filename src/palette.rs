@@ -1,7 +1,34 @@
 use crate::blocks;
+use crate::buffer;
 use crate::img;
+use crate::Error;
 use std::collections::HashMap;
 
+/// How much work goes into choosing the colors of a palette.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteEffort {
+    /// Count the colors of a sample of the pixels and keep the most common ones.
+    #[default]
+    Sampled,
+    /// Count the colors of every pixel and keep the ones saving the most bits. At most
+    /// `candidates` colors are counted at once, the least common ones are dropped when there are
+    /// too many.
+    Full { candidates: usize },
+}
+
+impl PaletteEffort {
+    /// Return the effort for a level between 0 (sampled) and 9. Each level doubles the number of
+    /// candidate colors, starting from 64.
+    pub fn from_level(level: u8) -> Self {
+        match level {
+            0 => Self::Sampled,
+            level => Self::Full {
+                candidates: 32 << level.min(9),
+            },
+        }
+    }
+}
+
 /// A palette containing (almost) all the colors of an image. The palette can be created from a
 /// random(ish) sample. `FullPalette` is used to create the palette with only the most common
 /// colors.
@@ -21,42 +48,306 @@ impl<const CHANNELS: usize> ImagePalette<CHANNELS> {
     }
 }
 
-impl<const CHANNELS: usize> From<&[u8]> for ImagePalette<CHANNELS> {
-    fn from(raw: &[u8]) -> Self {
+impl<const CHANNELS: usize> ImagePalette<CHANNELS> {
+    /// Sample the colors of the value of the pixels of an image.
+    pub fn from_pixels(pixels: &[img::Pixel<CHANNELS>]) -> Self {
+        let mut image_palette = Self::new();
+        for pixel in pixels.iter().step_by(Self::SAMPLE_FRENQUENCY / 2) {
+            if !blocks::Gray::is_gray(pixel) {
+                image_palette.increment_color(*pixel);
+            }
+        }
+        image_palette
+    }
+
+    /// Count the colors of every pixel, except the ones repeating the previous pixel since they
+    /// end up in a repeating block. The counts of at most `candidates` colors are kept with the
+    /// algorithm of Misra and Gries: when a new color does not fit, every count is decremented
+    /// and the colors that reach 0 are dropped. A count is therefore too small by at most
+    /// `pixels.len() / (candidates + 1)`.
+    pub fn from_all_pixels(pixels: &[img::Pixel<CHANNELS>], candidates: usize) -> Self {
         let mut image_palette = Self::new();
-        let step = Self::SAMPLE_FRENQUENCY / 2;
-        for chunk in raw.chunks_exact(CHANNELS * 2).step_by(step) {
-            let pixel =
-                img::Pixel::<CHANNELS>::compute_forward(&chunk[0..CHANNELS], &chunk[CHANNELS..]);
-            if !blocks::Gray::is_gray(&pixel) {
-                image_palette.increment_color(pixel);
+        let candidates = candidates.max(1);
+        let mut previous = None;
+        for pixel in pixels {
+            if previous == Some(pixel) || blocks::Gray::is_gray(pixel) {
+                previous = Some(pixel);
+                continue;
+            }
+            previous = Some(pixel);
+            if let Some(count) = image_palette.0.get_mut(pixel) {
+                *count += 1;
+            } else if image_palette.0.len() < candidates {
+                image_palette.0.insert(*pixel, 1);
+            } else {
+                image_palette.0.retain(|_, count| {
+                    *count -= 1;
+                    *count > 0
+                });
             }
         }
         image_palette
     }
 }
 
-#[derive(Debug)]
-pub struct Palette<const CHANNELS: usize>(pub Vec<img::Pixel<CHANNELS>>);
+/// Number of slots of the table of a [`Palette`], 4 times the number of colors so that they rarely
+/// collide. The slot of a color is its hash, see [`img::Pixel::hash`], modulo the number of slots.
+const SLOTS: usize = 64;
+
+/// The colors that can be encoded with a [`blocks::Color`] block. The colors are indexed by
+/// their hash in a small open addressing table so that finding a color usually takes one probe.
+#[derive(Debug, Clone)]
+pub struct Palette<const CHANNELS: usize> {
+    colors: Vec<img::Pixel<CHANNELS>>,
+    /// The index of the colors in `colors` at the slot of their hash, or of the next free slot
+    /// after it, [`Palette::EMPTY`] for the free slots.
+    slots: [u8; SLOTS],
+}
 
 impl<const CHANNELS: usize> Palette<CHANNELS> {
+    /// Number of bits used to store the number of colors in a palette.
+    pub const LEN_BITS: usize = 5;
+    const EMPTY: u8 = u8::MAX;
+
+    pub fn new(colors: Vec<img::Pixel<CHANNELS>>) -> Self {
+        let mut slots = [Self::EMPTY; SLOTS];
+        for (idx, color) in colors.iter().enumerate() {
+            let mut slot = color.hash() % SLOTS;
+            while slots[slot] != Self::EMPTY {
+                slot = (slot + 1) % SLOTS;
+            }
+            slots[slot] = idx as u8;
+        }
+        Self { colors, slots }
+    }
+
+    /// The colors of the palette, in the order of their index.
+    pub fn colors(&self) -> &[img::Pixel<CHANNELS>] {
+        &self.colors
+    }
+
+    /// Choose the colors of the palette of the value of some pixels.
+    pub fn with_effort(pixels: &[img::Pixel<CHANNELS>], effort: PaletteEffort) -> Self {
+        match effort {
+            PaletteEffort::Sampled => Self::from(ImagePalette::from_pixels(pixels)),
+            PaletteEffort::Full { candidates } => {
+                Self::from_savings(ImagePalette::from_all_pixels(pixels, candidates))
+            }
+        }
+    }
+
+    /// Keep the colors saving the most bits, see [`Palette::saved_bits`]. The colors that cost
+    /// more than they save are dropped.
+    pub fn from_savings(image_palette: ImagePalette<CHANNELS>) -> Self {
+        let mut colors = image_palette
+            .0
+            .into_iter()
+            .map(|(pixel, count)| (pixel, Self::saved_bits(&pixel, count)))
+            .filter(|(_, saved)| *saved > 0)
+            .collect::<Vec<_>>();
+        colors.sort_unstable_by_key(|(pixel, saved)| (-saved, pixel.0));
+        colors.truncate(blocks::Color::MAX);
+        Self::new(colors.into_iter().map(|(pixel, _)| pixel).collect())
+    }
+
+    /// Estimate the number of bits saved by a color of the palette used `count` times. Without
+    /// the palette, the first use is a pixel block and the following ones are either a pixel block
+    /// or an offset, whichever is the smallest. The color itself costs a pixel block in the
+    /// palette, which cancels out the bits saved by the first use.
+    fn saved_bits(pixel: &img::Pixel<CHANNELS>, count: usize) -> isize {
+        let pixel_bits = blocks::Pixel::encode(pixel).bit_count as isize;
+        let other_bits = pixel_bits.min(blocks::Offset::TOTAL_BITS as isize);
+        let color_bits = blocks::Color::TOTAL_BITS as isize;
+        (count as isize - 1) * (other_bits - color_bits) - color_bits
+    }
+
+    /// Push the number of colors followed by the colors.
+    pub fn encode(&self, buffer: &mut buffer::Buffer) {
+        buffer.push(blocks::Block::new(Self::LEN_BITS, self.colors.len()));
+        for pixel in self.colors.iter() {
+            buffer.push(blocks::Pixel::encode(pixel));
+        }
+    }
+
+    /// Read a palette pushed by [`Palette::encode`].
+    pub fn decode<R: std::io::Read>(
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<Vec<img::Pixel<CHANNELS>>, Error> {
+        let len = buffer.next_n_bits(Self::LEN_BITS)?;
+        if len > blocks::Color::MAX {
+            return Err(Error::InvalidData("the palette has too many colors"));
+        }
+        (0..len)
+            .map(|_| {
+                let (code, encoded_value) = buffer.next_raw_block::<CHANNELS>()?;
+                blocks::Pixel::<CHANNELS>::decode(encoded_value, code)
+            })
+            .collect()
+    }
+
+    /// Return the index of `pixel` in the palette. `hash` is the hash of the pixel, see
+    /// [`img::Pixel::hash`].
     #[inline]
-    pub fn get(&self, pixel: &img::Pixel<CHANNELS>) -> Option<usize> {
-        for (idx, pixel_in_palette) in self.0.iter().enumerate() {
-            if pixel_in_palette == pixel {
-                return Some(idx);
+    pub fn get(&self, pixel: &img::Pixel<CHANNELS>, hash: usize) -> Option<usize> {
+        let mut slot = hash % SLOTS;
+        loop {
+            let idx = self.slots[slot];
+            if idx == Self::EMPTY {
+                return None;
             }
+            if self.colors[idx as usize] == *pixel {
+                return Some(idx as usize);
+            }
+            slot = (slot + 1) % SLOTS;
         }
-        None
     }
 }
 
 impl<const CHANNELS: usize> From<ImagePalette<CHANNELS>> for Palette<CHANNELS> {
     fn from(image_palette: ImagePalette<CHANNELS>) -> Self {
         let mut colors = image_palette.0.into_iter().collect::<Vec<_>>();
-        colors.sort_unstable_by_key(|color| usize::MAX - color.1);
+        // The ties are broken by the value of the color so that the palette does not depend on
+        // the iteration order of the `HashMap`.
+        colors.sort_unstable_by_key(|(pixel, count)| (usize::MAX - count, pixel.0));
         colors.truncate(blocks::Color::MAX);
         let palette = colors.into_iter().map(|color| color.0).collect::<Vec<_>>();
-        Self(palette)
+        Self::new(palette)
+    }
+}
+
+/// The palettes of an image split in square tiles of `size` pixels, so that the colors of each
+/// part of the image get their own palette. The palettes of a row of tiles are stored from left to
+/// right before the first row of pixels of the tiles, only the palettes of the current row of
+/// tiles are therefore kept.
+#[derive(Clone)]
+pub struct Tiles<const CHANNELS: usize> {
+    size: usize,
+    img_width: usize,
+    effort: PaletteEffort,
+    /// The palettes of the current row of tiles, from left to right.
+    pub palettes: Vec<Palette<CHANNELS>>,
+}
+
+impl<const CHANNELS: usize> Tiles<CHANNELS> {
+    /// A single palette for the whole image, it is stored in the header.
+    pub fn single(palette: Palette<CHANNELS>) -> Self {
+        Self {
+            size: usize::MAX,
+            img_width: usize::MAX,
+            effort: PaletteEffort::default(),
+            palettes: vec![palette],
+        }
+    }
+
+    /// Tiles without colors. The palettes of each row of tiles are computed by
+    /// [`Tiles::compute_row`] when the pixels are known in advance, they stay empty otherwise.
+    pub fn new(img_width: usize, size: usize, effort: PaletteEffort) -> Self {
+        let columns = img_width.div_ceil(size);
+        Self {
+            size,
+            img_width,
+            effort,
+            palettes: (0..columns).map(|_| Palette::new(Vec::new())).collect(),
+        }
+    }
+
+    /// Compute the palette of every tile of a row of tiles from the value of its pixels, with the
+    /// effort of the tiles, see [`Palette::with_effort`].
+    pub fn compute_row(&mut self, pixels: &[img::Pixel<CHANNELS>]) {
+        let mut tiles = vec![Vec::new(); self.palettes.len()];
+        for row in pixels.chunks_exact(self.img_width) {
+            for (tile, tile_row) in tiles.iter_mut().zip(row.chunks(self.size)) {
+                tile.extend_from_slice(tile_row);
+            }
+        }
+        for (palette, tile) in self.palettes.iter_mut().zip(tiles) {
+            *palette = Palette::with_effort(&tile, self.effort);
+        }
+    }
+
+    /// Return the palette of the pixel at `x` in the current row of tiles.
+    #[inline]
+    pub fn get(&self, x: usize) -> &Palette<CHANNELS> {
+        &self.palettes[x / self.size]
+    }
+
+    /// Return the palettes of the current row of tiles.
+    pub fn row(&self) -> &[Palette<CHANNELS>] {
+        &self.palettes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn palette_lookup() {
+        // Colors whose hashes all land in the same slot are found after a few probes.
+        let colors = (0..1000)
+            .map(|red| img::Pixel([red, 3, -7]))
+            .filter(|pixel| pixel.hash() % 64 == 5)
+            .take(blocks::Color::MAX)
+            .collect::<Vec<_>>();
+        assert_eq!(colors.len(), blocks::Color::MAX);
+        let palette = Palette::new(colors.clone());
+        for (idx, color) in colors.iter().enumerate() {
+            assert_eq!(palette.get(color, color.hash()), Some(idx));
+        }
+        let missing = img::Pixel([1000, 3, -7]);
+        assert_eq!(palette.get(&missing, missing.hash()), None);
+        let empty = Palette::<3>::new(Vec::new());
+        assert_eq!(empty.get(&colors[0], colors[0].hash()), None);
+    }
+
+    #[test]
+    fn most_common_color() {
+        // The most common color is always kept, even with fewer candidates than colors.
+        let pixels = (0..1000)
+            .map(|idx| match idx % 3 {
+                0 => img::Pixel([40, 50, 60]),
+                _ => img::Pixel([idx as i16, 100, -100]),
+            })
+            .collect::<Vec<_>>();
+        let image_palette = ImagePalette::from_all_pixels(&pixels, 4);
+        assert!(image_palette.0.len() <= 4);
+        assert!(image_palette.0.contains_key(&img::Pixel([40, 50, 60])));
+        let palette = Palette::from_savings(image_palette);
+        assert_eq!(palette.colors(), [img::Pixel([40, 50, 60])]);
+    }
+
+    #[test]
+    fn tile_effort() {
+        // Two tiles of 16 pixels wide, each one alternating between its own 3 colors.
+        let colors = [
+            [10, 0, 0],
+            [0, 20, 0],
+            [0, 0, 30],
+            [40, 0, 0],
+            [0, 50, 0],
+            [0, 0, 60],
+        ];
+        let pixels = (0..32 * 16)
+            .map(|idx| img::Pixel(colors[idx % 32 / 16 * 3 + idx % 3]))
+            .collect::<Vec<_>>();
+        let lens = |effort| {
+            let mut tiles = Tiles::<3>::new(32, 16, effort);
+            tiles.compute_row(&pixels);
+            tiles
+                .row()
+                .iter()
+                .map(|palette| palette.colors().len())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lens(PaletteEffort::Full { candidates: 64 }), [3, 3]);
+        // The candidates bound the colors counted in each tile, a single one loses them all.
+        assert_eq!(lens(PaletteEffort::Full { candidates: 1 }), [0, 0]);
+    }
+
+    #[test]
+    fn row_of_tiles() {
+        // Only a row of tiles is kept, whatever the height of the image.
+        let tiles = Tiles::<3>::new(60_000, 16, PaletteEffort::Sampled);
+        assert_eq!(tiles.palettes.len(), 3750);
     }
 }
@@ -0,0 +1,211 @@
+use crate::img;
+use crate::Error;
+
+/// How the samples of a pixel are predicted from the pixels already encoded. The value of a pixel
+/// is the difference between the prediction and the actual samples.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Predictor {
+    /// The previous pixel in the order of the image, the last pixel of the previous row for the
+    /// first pixel of a row.
+    #[default]
+    Left,
+    /// The pixel above.
+    Up,
+    /// The average of the pixel on the left and the pixel above.
+    Average,
+    /// The pixel on the left, above or above on the left closest to `left + up - up_left`, like
+    /// in png.
+    Paeth,
+    /// The median edge detector of LOCO-I (JPEG-LS).
+    Med,
+    /// One of the other predictors is chosen for each row by the encoder, like the filters of
+    /// png. The choice is stored in a tag of [`Predictor::ROW_TAG_BITS`] bits before the first
+    /// block of the row.
+    Adaptive,
+}
+
+impl TryFrom<usize> for Predictor {
+    type Error = Error;
+
+    fn try_from(value: usize) -> Result<Self, Error> {
+        match value {
+            0 => Ok(Self::Left),
+            1 => Ok(Self::Up),
+            2 => Ok(Self::Average),
+            3 => Ok(Self::Paeth),
+            4 => Ok(Self::Med),
+            5 => Ok(Self::Adaptive),
+            _ => Err(Error::InvalidData("unknown predictor")),
+        }
+    }
+}
+
+impl Predictor {
+    /// The predictors that can be chosen for a row.
+    pub const ROWS: [Self; 5] = [Self::Left, Self::Up, Self::Average, Self::Paeth, Self::Med];
+    /// Number of bits of the tag of a row.
+    pub const ROW_TAG_BITS: usize = 3;
+
+    /// Return the predictor of a row from its tag.
+    pub fn from_row_tag(tag: usize) -> Result<Self, Error> {
+        Self::ROWS
+            .get(tag)
+            .copied()
+            .ok_or(Error::InvalidData("unknown predictor of a row"))
+    }
+
+    /// Predict a sample from the samples on the left, above and above on the left.
+    #[inline]
+    fn predict(self, left: i32, up: i32, up_left: i32) -> i32 {
+        match self {
+            // The adaptive predictor is replaced by the predictor of each row.
+            Self::Left | Self::Adaptive => left,
+            Self::Up => up,
+            Self::Average => (left + up) / 2,
+            Self::Paeth => {
+                let estimate = left + up - up_left;
+                let (to_left, to_up) = ((estimate - left).abs(), (estimate - up).abs());
+                let to_up_left = (estimate - up_left).abs();
+                if to_left <= to_up && to_left <= to_up_left {
+                    left
+                } else if to_up <= to_up_left {
+                    up
+                } else {
+                    up_left
+                }
+            }
+            Self::Med => {
+                if up_left >= left.max(up) {
+                    left.min(up)
+                } else if up_left <= left.min(up) {
+                    left.max(up)
+                } else {
+                    left + up - up_left
+                }
+            }
+        }
+    }
+}
+
+/// The pixels around the next pixel of the image: the previous one and the row above. Outside of
+/// the image, the pixel above is replaced by the pixel on the left and the pixel on the left by
+/// the pixel above. The first pixel of the image is predicted from a black pixel.
+pub struct Neighbors<S: img::Sample, const CHANNELS: usize> {
+    width: usize,
+    previous: [S; CHANNELS],
+    above: Vec<[S; CHANNELS]>,
+    row: Vec<[S; CHANNELS]>,
+}
+
+impl<S: img::Sample, const CHANNELS: usize> Neighbors<S, CHANNELS> {
+    pub fn new(width: usize) -> Self {
+        Self {
+            width,
+            previous: [S::default(); CHANNELS],
+            // The rows grow with the pixels pushed, the width of a decoded image is not trusted.
+            above: Vec::new(),
+            row: Vec::new(),
+        }
+    }
+
+    /// Return the prediction of the next pixel.
+    #[inline]
+    pub fn predict(&self, predictor: Predictor) -> [S; CHANNELS] {
+        self.predict_at(predictor, self.row.len(), self.previous)
+    }
+
+    /// Return the prediction of the pixel at `x` in the current row when the previous pixel is
+    /// `previous`.
+    #[inline]
+    fn predict_at(&self, predictor: Predictor, x: usize, previous: [S; CHANNELS]) -> [S; CHANNELS] {
+        if predictor == Predictor::Left {
+            return previous;
+        }
+        let (left, up, up_left) = if self.above.is_empty() {
+            (previous, previous, previous)
+        } else if x == 0 {
+            (self.above[0], self.above[0], self.above[0])
+        } else {
+            (previous, self.above[x], self.above[x - 1])
+        };
+        std::array::from_fn(|idx| {
+            S::from_i32(predictor.predict(
+                left[idx].to_i32(),
+                up[idx].to_i32(),
+                up_left[idx].to_i32(),
+            ))
+        })
+    }
+
+    /// Return the predictor of `candidates` that gives the cheapest values to the pixels of `row`,
+    /// the raw bytes of the next row. The adaptive predictor is not a candidate, the left one is
+    /// returned when there are no other candidates.
+    pub fn choose(&self, row: &[u8], candidates: &[Predictor]) -> Predictor {
+        candidates
+            .iter()
+            .filter(|predictor| **predictor != Predictor::Adaptive)
+            .map(|predictor| (self.cost(row, *predictor), *predictor))
+            .min_by_key(|(cost, predictor)| (*cost, *predictor as u8))
+            .map_or(Predictor::Left, |(_, predictor)| predictor)
+    }
+
+    /// Estimate the cost of the values of the pixels of `row` with `predictor`. The cost of a value
+    /// is the sum of the absolute value of its channels, like the heuristic of png, except that a
+    /// value equal to the previous one is free since it ends up in a repeating block.
+    fn cost(&self, row: &[u8], predictor: Predictor) -> usize {
+        let (mut cost, mut previous, mut previous_pixel) = (0, self.previous, None);
+        for (x, current) in row.chunks_exact(CHANNELS * S::BYTES).enumerate() {
+            let current = img::read_samples::<S, CHANNELS>(current);
+            let prediction = self.predict_at(predictor, x, previous);
+            let pixel = img::Pixel::<CHANNELS>::compute_forward(&prediction, &current);
+            if previous_pixel != Some(pixel) {
+                cost += pixel
+                    .0
+                    .iter()
+                    .map(|channel| channel.unsigned_abs() as usize)
+                    .sum::<usize>();
+            }
+            (previous, previous_pixel) = (current, Some(pixel));
+        }
+        cost
+    }
+
+    /// Record the samples of the next pixel.
+    #[inline]
+    pub fn push(&mut self, current: [S; CHANNELS]) {
+        self.previous = current;
+        self.row.push(current);
+        if self.row.len() == self.width {
+            std::mem::swap(&mut self.above, &mut self.row);
+            self.row.clear();
+        }
+    }
+}
+
+/// Return the value of every pixel of a raw image whose samples are of type `S` and the predictor
+/// of each row. The adaptive predictor chooses the predictor of each row among `candidates`.
+pub fn compute_pixels<S: img::Sample, const CHANNELS: usize>(
+    raw: &[u8],
+    width: usize,
+    predictor: Predictor,
+    candidates: &[Predictor],
+) -> (Vec<img::Pixel<CHANNELS>>, Vec<Predictor>) {
+    let mut neighbors = Neighbors::<S, CHANNELS>::new(width);
+    let mut pixels = Vec::with_capacity(raw.len() / (CHANNELS * S::BYTES));
+    let row_len = width * CHANNELS * S::BYTES;
+    let mut row_predictors = Vec::with_capacity(raw.len() / row_len.max(1));
+    for row in raw.chunks_exact(row_len) {
+        let row_predictor = match predictor {
+            Predictor::Adaptive => neighbors.choose(row, candidates),
+            predictor => predictor,
+        };
+        row_predictors.push(row_predictor);
+        for current in row.chunks_exact(CHANNELS * S::BYTES) {
+            let current = img::read_samples::<S, CHANNELS>(current);
+            let prediction = neighbors.predict(row_predictor);
+            neighbors.push(current);
+            pixels.push(img::Pixel::compute_forward(&prediction, &current));
+        }
+    }
+    (pixels, row_predictors)
+}
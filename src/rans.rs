@@ -0,0 +1,438 @@
+use crate::blocks;
+use crate::buffer;
+use crate::entropy;
+use crate::Error;
+use crate::U8_BITS;
+
+/// Number of bits of the probabilities, every symbol is coded with a frequency out of
+/// `2^PROB_BITS`.
+const PROB_BITS: usize = 12;
+const PROB_MASK: u32 = (1 << PROB_BITS) - 1;
+/// The lower bound of the state, it stays in `[L, 2^8 * L)` between two symbols.
+const L: u32 = 1 << 23;
+/// How fast the binary models adapt, a probability moves by `1 / 2^ADAPT_SHIFT` of its distance
+/// to the coded bit.
+const ADAPT_SHIFT: usize = 5;
+/// The context of the first type of block, no block precedes it.
+const NO_TYPE: usize = entropy::TYPES;
+/// Number of bits of the symbol of a type of block.
+const TYPE_BITS: usize = (usize::BITS - (NO_TYPE - 1).leading_zeros()) as usize;
+/// The most fields of a block, one per channel.
+const MAX_FIELDS: usize = 4;
+/// Number of symbols of a chunk. The symbols are encoded by chunks so that the encoder does not
+/// keep every symbol of the image, each chunk is preceded by its length in bytes.
+const CHUNK_SYMBOLS: usize = 1 << 16;
+
+/// An adaptive binary model: the probability that the next bit is a 0, out of `2^PROB_BITS`.
+#[derive(Debug, Clone, Copy)]
+struct Bit(u16);
+
+impl Bit {
+    const fn new() -> Self {
+        Self(1 << (PROB_BITS - 1))
+    }
+
+    /// Return the start and the frequency of `bit` and update the model.
+    #[inline]
+    fn encode(&mut self, bit: usize) -> (u32, u32) {
+        let zero = self.0 as u32;
+        self.update(bit);
+        match bit {
+            0 => (0, zero),
+            _ => (zero, (1 << PROB_BITS) - zero),
+        }
+    }
+
+    /// Return the bit of `slot` with its start and its frequency, and update the model.
+    #[inline]
+    fn decode(&mut self, slot: u32) -> (usize, u32, u32) {
+        let zero = self.0 as u32;
+        let bit = (slot >= zero) as usize;
+        self.update(bit);
+        match bit {
+            0 => (0, 0, zero),
+            _ => (1, zero, (1 << PROB_BITS) - zero),
+        }
+    }
+
+    /// The probability never reaches 0 or `2^PROB_BITS`: the last steps are smaller than 1.
+    #[inline]
+    fn update(&mut self, bit: usize) {
+        match bit {
+            0 => self.0 += ((1 << PROB_BITS) - self.0) >> ADAPT_SHIFT,
+            _ => self.0 -= self.0 >> ADAPT_SHIFT,
+        }
+    }
+}
+
+/// The models of an image, the same on both sides. The type of a block is coded bit by bit in a
+/// binary tree whose context is the type of the previous block. Each field of at most
+/// [`entropy::MAX_FIELD_BITS`] bits has a tree per type of block and per position in the block,
+/// the channels of the pixel blocks are therefore modeled apart. Everything else is coded with
+/// uniform probabilities.
+struct Models {
+    previous: usize,
+    types: Vec<Bit>,
+    fields: Vec<Bit>,
+}
+
+impl Models {
+    fn new() -> Self {
+        Self {
+            previous: NO_TYPE,
+            types: vec![Bit::new(); (NO_TYPE + 1) << TYPE_BITS],
+            fields: vec![Bit::new(); (NO_TYPE * MAX_FIELDS) << entropy::MAX_FIELD_BITS],
+        }
+    }
+
+    /// Return the tree of the type following the previous one.
+    #[inline]
+    fn types(&mut self) -> &mut [Bit] {
+        let start = self.previous << TYPE_BITS;
+        &mut self.types[start..start + (1 << TYPE_BITS)]
+    }
+
+    /// Return the tree of the field `idx` of the blocks of `symbol`.
+    #[inline]
+    fn field(&mut self, symbol: usize, idx: usize) -> &mut [Bit] {
+        let start = (symbol * MAX_FIELDS + idx) << entropy::MAX_FIELD_BITS;
+        &mut self.fields[start..start + (1 << entropy::MAX_FIELD_BITS)]
+    }
+}
+
+/// The symbols of the current chunk, each one packed as its start and its frequency, and the
+/// bytes of the chunks already encoded.
+struct Symbols {
+    symbols: Vec<u32>,
+    bytes: Vec<u8>,
+}
+
+impl Symbols {
+    #[inline]
+    fn push(&mut self, start: u32, freq: u32) {
+        if self.symbols.len() == CHUNK_SYMBOLS {
+            self.flush();
+        }
+        self.symbols.push((start << 16) | freq);
+    }
+
+    /// Encode the symbols of the current chunk and write its length, its final state and its
+    /// bytes in the order they are read by the decoder.
+    fn flush(&mut self) {
+        let mut bytes = Vec::with_capacity(self.symbols.len() / U8_BITS + 4);
+        let mut state = L;
+        for symbol in self.symbols.drain(..).rev() {
+            let (start, freq) = (symbol >> 16, symbol & 0xffff);
+            let max = ((L >> PROB_BITS) << U8_BITS) * freq;
+            while state >= max {
+                bytes.push(state as u8);
+                state >>= U8_BITS;
+            }
+            state = ((state / freq) << PROB_BITS) + state % freq + start;
+        }
+        bytes.extend(state.to_le_bytes());
+        bytes.reverse();
+        self.bytes
+            .extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        self.bytes.append(&mut bytes);
+    }
+
+    /// Push the `bits` bits of `value` in the binary tree `tree`, the most significant first.
+    #[inline]
+    fn push_tree(&mut self, tree: &mut [Bit], bits: usize, value: usize) {
+        let mut node = 1;
+        for idx in (0..bits).rev() {
+            let bit = (value >> idx) & 1;
+            let (start, freq) = tree[node].encode(bit);
+            self.push(start, freq);
+            node = (node << 1) | bit;
+        }
+    }
+
+    /// Push the `bits` bits of `value` with uniform probabilities, one by one. The data that is not
+    /// a block is not always read the way it is written, a region is pushed at once but read a
+    /// field at a time for example.
+    #[inline]
+    fn push_bits(&mut self, bits: usize, value: u128) {
+        for idx in (0..bits).rev() {
+            let bit = (value >> idx) as u32 & 1;
+            self.push(bit << (PROB_BITS - 1), 1 << (PROB_BITS - 1));
+        }
+    }
+
+    /// Push the `bits` bits of `value` with uniform probabilities, by chunks of at most
+    /// [`PROB_BITS`] bits.
+    #[inline]
+    fn push_bypass(&mut self, bits: usize, value: u128) {
+        let mut remaining = bits;
+        while remaining > 0 {
+            let chunk_bits = remaining.min(PROB_BITS);
+            remaining -= chunk_bits;
+            let chunk = (value >> remaining) as u32 & ((1 << chunk_bits) - 1);
+            let freq_bits = PROB_BITS - chunk_bits;
+            self.push(chunk << freq_bits, 1 << freq_bits);
+        }
+    }
+}
+
+/// The encoder of the blocks following the header with adaptive binary models and a range
+/// asymmetric numeral system. rANS decodes the symbols in the reverse order of their encoding,
+/// they are therefore recorded in the order of the image and encoded backward once a chunk of
+/// [`CHUNK_SYMBOLS`] symbols is complete. The models are shared by every chunk. It cannot be
+/// streamed, the bytes of the chunks are only returned by [`RansEncoder::finish`].
+pub struct RansEncoder {
+    models: Models,
+    symbols: Symbols,
+}
+
+impl RansEncoder {
+    pub fn new() -> Self {
+        Self {
+            models: Models::new(),
+            symbols: Symbols {
+                symbols: Vec::new(),
+                bytes: Vec::new(),
+            },
+        }
+    }
+
+    /// Push a block with the prefix code `code`.
+    #[inline]
+    pub fn push_block<const CHANNELS: usize>(&mut self, code: usize, block: blocks::Block) {
+        let symbol = entropy::symbol(code);
+        self.symbols
+            .push_tree(self.models.types(), TYPE_BITS, symbol);
+        self.models.previous = symbol;
+        let (payload, extra, extra_bits) = entropy::split::<CHANNELS>(code, &block);
+        let (bits, count) = blocks::Typ::<CHANNELS>::fields(code);
+        if bits <= entropy::MAX_FIELD_BITS {
+            for (idx, field) in entropy::fields(payload, bits, count).enumerate() {
+                self.symbols
+                    .push_tree(self.models.field(symbol, idx), bits, field);
+            }
+        } else {
+            self.symbols.push_bypass(bits * count, payload as u128);
+        }
+        // The decoder reads the extra bits of the offsets apart.
+        self.symbols.push_bits(extra_bits, extra as u128);
+    }
+
+    /// Push data that is not a block, with uniform probabilities.
+    #[inline]
+    pub fn push(&mut self, block: blocks::Block) {
+        self.symbols.push_bits(block.bit_count, block.value);
+    }
+
+    /// Encode the last chunk and return the bytes of every chunk, to write after the header.
+    pub fn finish(mut self) -> Vec<u8> {
+        if !self.symbols.symbols.is_empty() {
+            self.symbols.flush();
+        }
+        self.symbols.bytes
+    }
+}
+
+/// The decoder of the blocks coded by [`RansEncoder`]. The chunks are read as the symbols are
+/// decoded.
+pub struct RansDecoder {
+    models: Models,
+    state: u32,
+    /// The number of symbols decoded in the current chunk.
+    symbols: usize,
+    /// The number of bytes of the current chunk that are not read yet.
+    remaining: usize,
+}
+
+impl RansDecoder {
+    pub fn new() -> Self {
+        // The first chunk is read with the first symbol, like the following ones.
+        Self {
+            models: Models::new(),
+            state: L,
+            symbols: CHUNK_SYMBOLS,
+            remaining: 0,
+        }
+    }
+
+    /// Check that the last chunk was read entirely, the state of rANS then goes back to its
+    /// initial value.
+    pub fn finish(&self) -> Result<(), Error> {
+        match self.remaining == 0 && self.state == L {
+            true => Ok(()),
+            false => Err(Error::InvalidData("invalid rANS chunk")),
+        }
+    }
+
+    /// Read the next block and return its prefix code and its payload, like
+    /// [`buffer::BufferDecoder::next_raw_block`].
+    #[inline]
+    pub fn next_block<const CHANNELS: usize, R: std::io::Read>(
+        &mut self,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<(usize, usize), Error> {
+        let symbol = self.next_tree(Tree::Type, TYPE_BITS, buffer)?;
+        let code = *blocks::Typ::<CHANNELS>::CODES
+            .get(symbol)
+            .ok_or(Error::InvalidData("unknown type of block"))?;
+        self.models.previous = symbol;
+        let (bits, count) = blocks::Typ::<CHANNELS>::fields(code);
+        if bits > entropy::MAX_FIELD_BITS {
+            return Ok((code, self.next_bypass(bits * count, buffer)?));
+        }
+        let mut payload = 0;
+        for idx in 0..count {
+            payload = (payload << bits) | self.next_tree(Tree::Field(symbol, idx), bits, buffer)?;
+        }
+        Ok((code, payload))
+    }
+
+    /// Read `bits` bits of data that is not a block.
+    #[inline]
+    pub fn next_bits<R: std::io::Read>(
+        &mut self,
+        bits: usize,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<usize, Error> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = self.slot(buffer)? >> (PROB_BITS - 1);
+            self.advance(bit << (PROB_BITS - 1), 1 << (PROB_BITS - 1), buffer)?;
+            value = (value << 1) | bit as usize;
+        }
+        Ok(value)
+    }
+
+    /// Read `bits` bits of a payload coded with uniform probabilities.
+    #[inline]
+    fn next_bypass<R: std::io::Read>(
+        &mut self,
+        bits: usize,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<usize, Error> {
+        let mut value: u128 = 0;
+        let mut remaining = bits;
+        while remaining > 0 {
+            let chunk_bits = remaining.min(PROB_BITS);
+            remaining -= chunk_bits;
+            let freq_bits = PROB_BITS - chunk_bits;
+            let chunk = self.slot(buffer)? >> freq_bits;
+            self.advance(chunk << freq_bits, 1 << freq_bits, buffer)?;
+            value = (value << chunk_bits) | chunk as u128;
+        }
+        Ok(value as usize)
+    }
+
+    /// Read `bits` bits from a binary tree of the models.
+    #[inline]
+    fn next_tree<R: std::io::Read>(
+        &mut self,
+        tree: Tree,
+        bits: usize,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<usize, Error> {
+        let mut node = 1;
+        for _ in 0..bits {
+            let slot = self.slot(buffer)?;
+            let model = match tree {
+                Tree::Type => &mut self.models.types()[node],
+                Tree::Field(symbol, idx) => &mut self.models.field(symbol, idx)[node],
+            };
+            let (bit, start, freq) = model.decode(slot);
+            self.advance(start, freq, buffer)?;
+            node = (node << 1) | bit;
+        }
+        Ok(node - (1 << bits))
+    }
+
+    /// Return the slot of the next symbol in the state, the next chunk is read first when the
+    /// current one is complete.
+    #[inline]
+    fn slot<R: std::io::Read>(
+        &mut self,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<u32, Error> {
+        if self.symbols == CHUNK_SYMBOLS {
+            self.finish()?;
+            self.remaining = buffer.read_bits(u32::BITS as usize)?;
+            self.state = 0;
+            for _ in 0..u32::BITS as usize / U8_BITS {
+                self.state = (self.state << U8_BITS) | self.next_byte(buffer)?;
+            }
+            self.symbols = 0;
+        }
+        Ok(self.state & PROB_MASK)
+    }
+
+    /// Read the next byte of the current chunk.
+    #[inline]
+    fn next_byte<R: std::io::Read>(
+        &mut self,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<u32, Error> {
+        if self.remaining == 0 {
+            return Err(Error::InvalidData("invalid rANS chunk"));
+        }
+        self.remaining -= 1;
+        Ok(buffer.read_bits(U8_BITS)? as u32)
+    }
+
+    /// Remove the symbol at `start` with `freq` from the state and read the bytes it needs.
+    #[inline]
+    fn advance<R: std::io::Read>(
+        &mut self,
+        start: u32,
+        freq: u32,
+        buffer: &mut buffer::BufferDecoder<R>,
+    ) -> Result<(), Error> {
+        // The arithmetic wraps on corrupted data instead of overflowing, the checksum catches it.
+        self.state = freq
+            .wrapping_mul(self.state >> PROB_BITS)
+            .wrapping_add(self.state & PROB_MASK)
+            .wrapping_sub(start);
+        while self.state < L {
+            self.state = (self.state << U8_BITS) | self.next_byte(buffer)?;
+        }
+        self.symbols += 1;
+        Ok(())
+    }
+}
+
+/// A binary tree of the [`Models`].
+#[derive(Clone, Copy)]
+enum Tree {
+    Type,
+    Field(usize, usize),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunks() {
+        // Enough symbols for a few chunks, the last one is not complete.
+        let values = (0..CHUNK_SYMBOLS * 5 / 2).map(|idx| idx % 3 % 2);
+        let mut encoder = RansEncoder::new();
+        values
+            .clone()
+            .for_each(|value| encoder.push(blocks::Block::new(1, value)));
+        let bytes = encoder.finish();
+        let mut buffer = buffer::BufferDecoder::new(&bytes[..]);
+        let mut decoder = RansDecoder::new();
+        for value in values {
+            assert_eq!(decoder.next_bits(1, &mut buffer), Ok(value));
+        }
+        assert_eq!(decoder.finish(), Ok(()));
+
+        // A chunk longer than its symbols is not read entirely.
+        let mut corrupted = bytes.clone();
+        corrupted[3] += 1;
+        let mut buffer = buffer::BufferDecoder::new(&corrupted[..]);
+        let mut decoder = RansDecoder::new();
+        let result = (0..CHUNK_SYMBOLS + 1).try_for_each(|_| {
+            decoder.next_bits(1, &mut buffer)?;
+            Ok(())
+        });
+        assert_eq!(result, Err(Error::InvalidData("invalid rANS chunk")));
+    }
+}
@@ -1,21 +1,24 @@
+use crate::blocks;
+use crate::encoder;
 use crate::img;
+use std::rc::Rc;
 
+/// A rectangle of pixels that all have the same value, `fill`. Since the value of a pixel is the
+/// delta with the previous one, flat areas are blobs whose `fill` is 0 except maybe for the first
+/// column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Blob<const CHANNELS: usize> {
-    color: [u8; CHANNELS], //img::Pixel<CHANNELS>,
-    x: usize,
-    y: usize,
-    width: usize,
-    height: usize,
+    pub fill: img::Pixel<CHANNELS>,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
 }
 
 impl<const CHANNELS: usize> Blob<CHANNELS> {
-    pub fn new(color: img::Pixel<CHANNELS>, start_x: usize, end_x: usize, start_y: usize) -> Self {
-        let mut color: [u8; CHANNELS] = [255; CHANNELS];
-        color[0] += 93 * (start_x * end_x) as u8;
-        color[1] += 23 * (start_x * end_x) as u8;
-        color[2] += 219 * (start_x * end_x) as u8;
+    pub fn new(fill: img::Pixel<CHANNELS>, start_x: usize, end_x: usize, start_y: usize) -> Self {
         Self {
-            color,
+            fill,
             x: start_x,
             y: start_y,
             width: end_x - start_x,
@@ -27,227 +30,309 @@ impl<const CHANNELS: usize> Blob<CHANNELS> {
         self.width * self.height
     }
 
-    pub fn is_big_enough(&self) -> bool {
-        self.height * (self.width / 64 * 9) > 71
+    /// A blob is worth its block when the repeating blocks it replaces are bigger than it by more
+    /// than `min_saved_bits`.
+    pub fn is_big_enough(&self, min_saved_bits: usize) -> bool {
+        let repeating_bits =
+            self.width.div_ceil(blocks::Repeating::MAX) * blocks::Repeating::TOTAL_BITS;
+        self.height * repeating_bits > min_saved_bits
     }
 
     pub fn is_inside(&self, x: usize, y: usize) -> bool {
-        if x >= self.x && x <= self.x + self.width {
-            return y >= self.y && y <= self.y + self.height;
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+/// A span of pixels `[start, end)` of a row.
+pub type Span = (usize, usize);
+
+/// A set of pixels with the same value, `fill`, of any shape. It is stored as the spans of each
+/// of its rows, starting at the row `y`, the identical rows can share their spans.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region<const CHANNELS: usize> {
+    pub fill: img::Pixel<CHANNELS>,
+    pub y: usize,
+    pub rows: Vec<Rc<[Span]>>,
+}
+
+impl<const CHANNELS: usize> Region<CHANNELS> {
+    /// Estimation of the number of bits of the first pixel of a span if it is not in a region.
+    const SPAN_START_BITS: usize = 10;
+
+    /// Create a region from the index of its pixels in an image of `img_width` pixels per row.
+    pub fn from_indices(
+        fill: img::Pixel<CHANNELS>,
+        mut indices: Vec<usize>,
+        img_width: usize,
+    ) -> Self {
+        indices.sort_unstable();
+        let y = indices[0] / img_width;
+        let mut rows: Vec<Vec<Span>> = Vec::new();
+        for idx in indices {
+            let (x, row) = (idx % img_width, idx / img_width - y);
+            if rows.len() <= row {
+                rows.resize(row + 1, Vec::new());
+            }
+            match rows[row].last_mut() {
+                Some(span) if span.1 == x => span.1 += 1,
+                _ => rows[row].push((x, x + 1)),
+            }
         }
-        false
+        let rows = rows.into_iter().map(Rc::from).collect();
+        Self { fill, y, rows }
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Return the first and the last column (excluded) covered by the region.
+    pub fn bounds(&self) -> (usize, usize) {
+        self.rows
+            .iter()
+            .flat_map(|row| row.iter())
+            .fold((usize::MAX, 0), |(left, right), span| {
+                (left.min(span.0), right.max(span.1))
+            })
+    }
+
+    /// Return the position of the first pixel of the region, in the order of the image.
+    pub fn first_pixel(&self) -> (usize, usize) {
+        (self.rows[0][0].0, self.y)
     }
 
-    pub fn generate_map(&self) -> Vec<usize> {
-        let mut map = Vec::with_capacity(self.width * self.height);
-        for y in 0..self.height {
-            for x in 0..self.width {
-                map.push((x + self.x) + (self.y + y) * self.width);
+    pub fn is_rectangle(&self) -> bool {
+        self.rows
+            .iter()
+            .all(|row| row.len() == 1 && row[0] == self.rows[0][0])
+    }
+
+    /// Estimate the number of bits needed to encode the pixels of the region without it: the
+    /// first pixel of each span and the repeating blocks for the others.
+    pub fn saved_bits(&self) -> usize {
+        let repeating_bits = blocks::Typ::<CHANNELS>::Repeating.size();
+        self.rows
+            .iter()
+            .flat_map(|row| row.iter())
+            .map(|(start, end)| {
+                Self::SPAN_START_BITS
+                    + (end - start - 1).div_ceil(blocks::Repeating::MAX) * repeating_bits
+            })
+            .sum()
+    }
+
+    /// Return the index of every pixel of the region in an image of `img_width` pixels per row.
+    pub fn generate_map(&self, img_width: usize) -> Vec<usize> {
+        let mut map = Vec::new();
+        for (row, spans) in self.rows.iter().enumerate() {
+            for (start, end) in spans.iter() {
+                map.extend((*start..*end).map(|x| x + (self.y + row) * img_width));
             }
         }
         map
     }
 }
 
+impl<const CHANNELS: usize> From<Blob<CHANNELS>> for Region<CHANNELS> {
+    fn from(blob: Blob<CHANNELS>) -> Self {
+        Self {
+            fill: blob.fill,
+            y: blob.y,
+            rows: vec![Rc::from([(blob.x, blob.x + blob.width)]); blob.height],
+        }
+    }
+}
+
+/// The regions of an image. They are made of:
+/// - the blobs: the rows of pixels with the same value that are wider than
+///   [`encoder::EncoderOptions::blob_min_width`] and stacked on top of each other,
+/// - the connected pixels with the same value found by [`ColorBlobs`] outside of the blobs, when
+///   they are cheaper to encode as a region.
+#[derive(Default, Clone)]
 pub struct Blobs<const CHANNELS: usize> {
     img_width: usize,
-    img_height: usize,
-    pixels: Vec<img::Pixel<CHANNELS>>,
-    pub blobs: Vec<Blob<CHANNELS>>,
-    map: Vec<usize>,
+    pub regions: Vec<Region<CHANNELS>>,
+    /// The index of the region covering each pixel of the image, `u32::MAX` if none. It is empty
+    /// when the image has no regions.
+    map: Vec<u32>,
 }
 
 impl<const CHANNELS: usize> Blobs<CHANNELS> {
-    pub fn new(raw: &[u8], img_width: usize, img_height: usize) -> Self {
-        let mut previous_chunk = &[0; CHANNELS][..];
-        let mut pixels = Vec::with_capacity(raw.len() / CHANNELS);
-        for current in raw.chunks_exact(CHANNELS) {
-            pixels.push(img::Pixel::<CHANNELS>::compute_forward(
-                previous_chunk,
-                current,
-            ));
-            previous_chunk = current;
-        }
-
-        Self {
+    /// Find the regions of an image from the value of its pixels. Nothing is computed when the
+    /// options disable both the blobs and the regions.
+    pub fn new(
+        pixels: &[img::Pixel<CHANNELS>],
+        img_width: usize,
+        options: &encoder::EncoderOptions,
+    ) -> Self {
+        let mut blobs = Self {
             img_width,
-            img_height,
-            map: vec![usize::MAX; pixels.len()],
-            pixels,
-            blobs: Vec::new(),
+            regions: Vec::new(),
+            map: Vec::new(),
+        };
+        let (has_blobs, has_regions) = (
+            options.blob_min_width != usize::MAX,
+            options.region_min_size != usize::MAX,
+        );
+        if !has_blobs && !has_regions {
+            return blobs;
         }
+        blobs.map = vec![u32::MAX; pixels.len()];
+        if has_blobs {
+            for blob in Self::compute(pixels, img_width, options) {
+                blobs.add(Region::from(blob));
+            }
+        }
+        let color_blobs = if has_regions {
+            let is_visited = blobs.map.iter().map(|idx| *idx != u32::MAX).collect();
+            let mut color_blobs =
+                ColorBlobs::new(pixels, img_width, is_visited, options.region_min_size);
+            color_blobs.compute();
+            color_blobs.blobs
+        } else {
+            Vec::new()
+        };
+        for indices in color_blobs {
+            let region = Region::from_indices(pixels[indices[0]], indices, img_width);
+            let (left, right) = region.bounds();
+            let fits = right - left <= blocks::Blob::MAX && region.height() <= blocks::Blob::MAX;
+            if fits && blocks::Region::<CHANNELS>::bit_count(&region) < region.saved_bits() {
+                blobs.add(region);
+            }
+        }
+        if blobs.regions.is_empty() {
+            blobs.map = Vec::new();
+        }
+        blobs
     }
 
-    pub fn compute(&mut self) {
+    fn compute(
+        pixels: &[img::Pixel<CHANNELS>],
+        img_width: usize,
+        options: &encoder::EncoderOptions,
+    ) -> Vec<Blob<CHANNELS>> {
         let mut closed_blobs = Vec::new();
         let mut growing_blobs: Vec<Blob<CHANNELS>> = Vec::new();
 
-        for y in 0..self.img_height {
-            let mut previous = self.pixels[y * self.img_width];
-            let mut is_repeating = false;
-            let mut starting_x = 0;
-            for x in 1..self.img_width {
-                let current = self.pixels[y * self.img_width + x];
-                if previous == current {
-                    if !is_repeating {
-                        starting_x = x - 1;
-                        is_repeating = true;
-                    }
-                } else {
-                    if is_repeating {
-                        if x - starting_x > 64 {
-                            let mut is_blob_added = false;
-                            for blob in growing_blobs.iter_mut() {
-                                if blob.x == starting_x && blob.width == x - starting_x {
-                                    blob.height += 1;
-                                    is_blob_added = true;
-                                    break;
-                                }
-                            }
-                            if !is_blob_added {
-                                growing_blobs.push(Blob::new(current, starting_x, x, y));
-                            }
-                        }
-                        is_repeating = false;
+        for (y, row) in pixels.chunks_exact(img_width).enumerate() {
+            let mut start_x = 0;
+            for x in 1..=img_width {
+                if x < img_width && row[x] == row[start_x] {
+                    continue;
+                }
+                if x - start_x > options.blob_min_width {
+                    let end_x = x.min(start_x + blocks::Blob::MAX);
+                    let growing_blob = growing_blobs.iter_mut().find(|blob| {
+                        blob.x == start_x
+                            && blob.width == end_x - start_x
+                            && blob.fill == row[start_x]
+                            && blob.height < blocks::Blob::MAX
+                    });
+                    match growing_blob {
+                        Some(blob) => blob.height += 1,
+                        None => growing_blobs.push(Blob::new(row[start_x], start_x, end_x, y)),
                     }
                 }
-                previous = current;
+                start_x = x;
             }
-            //growing_blobs.sort_unstable_by(|a, b| (b.size()).cmp(&a.size()));
-            //growing_blobs.truncate(10);
             let (mut newly_closed_blobs, still_growing_blobs): (Vec<_>, Vec<_>) = growing_blobs
                 .into_iter()
                 .partition(|blob| blob.y + blob.height == y);
             growing_blobs = still_growing_blobs;
             closed_blobs.append(&mut newly_closed_blobs);
         }
-        //closed_blobs.append(&mut growing_blobs);
-        //closed_blobs.sort_unstable_by(|a, b| (b.size()).cmp(&a.size()));
-        //closed_blobs.truncate(512);
+        closed_blobs.append(&mut growing_blobs);
+        closed_blobs.retain(|blob| blob.is_big_enough(options.blob_min_saved_bits));
         closed_blobs
-            .iter()
-            .filter(|blob| blob.is_big_enough())
-            .collect::<Vec<_>>();
-        self.blobs = closed_blobs;
-        self.generate_blobs_map();
     }
 
-    pub fn generate_blobs_map(&mut self) {
-        for (blob_idx, blob) in self.blobs.iter().enumerate() {
-            blob.generate_map()
-                .iter()
-                .for_each(|idx| self.map[*idx] = blob_idx);
+    fn add(&mut self, region: Region<CHANNELS>) {
+        let region_idx = self.regions.len() as u32;
+        for idx in region.generate_map(self.img_width) {
+            self.map[idx] = region_idx;
         }
+        self.regions.push(region);
     }
 
-    pub fn is_inside_blob(&self, idx: usize) -> bool {
-        self.map[idx] != usize::MAX
-    }
-
-    pub fn size(&mut self) -> usize {
-        self.blobs.len() * 9
-    }
-
-    // pub fn into_test(mut self) -> Vec<u8> {
-    //     let mut bytes = Vec::with_capacity(self.pixels.len());
-    //     let mut color = [255; CHANNELS];
-
-    //     for idx in 0..self.pixels.len() {
-    //         let x = idx % self.img_width;
-    //         let y = idx / self.img_width;
-    //         let mut is_inside_blob = false;
-    //         for blob in self.blobs.iter() {
-    //             if blob.is_inside(x, y) {
-    //                 is_inside_blob = true;
-    //                 bytes.append(&mut blob.color.to_vec());
-    //                 break;
-    //             }
-    //         }
-    //         if !is_inside_blob {
-    //             let mut p = self.pixels[idx]
-    //                 .0
-    //                 .into_iter()
-    //                 .map(|c| c as u8)
-    //                 .collect::<Vec<_>>();
-    //             p[CHANNELS - 1] = 255;
-    //             bytes.append(&mut p);
-    //         }
-    //     }
-
-    //     bytes
-    // }
+    /// Return the region covering the pixel at `idx`, if any, and whether it is the first pixel
+    /// of the region.
+    #[inline]
+    pub fn at(&self, idx: usize) -> Option<(&Region<CHANNELS>, bool)> {
+        match self.map.get(idx) {
+            Some(region_idx) if *region_idx != u32::MAX => {
+                let region = &self.regions[*region_idx as usize];
+                let (x, y) = region.first_pixel();
+                Some((region, x + y * self.img_width == idx))
+            }
+            _ => None,
+        }
+    }
 }
 
-pub struct ColorBlobs<const CHANNELS: usize> {
+/// Find the 4-connected pixels with the same value with a flood fill.
+pub struct ColorBlobs<'a, const CHANNELS: usize> {
     img_width: usize,
-    pixels: Vec<img::Pixel<CHANNELS>>,
+    pixels: &'a [img::Pixel<CHANNELS>],
     is_visited: Vec<bool>,
-    blobs: Vec<Vec<usize>>,
+    /// The blobs with at most `min_size` pixels are dropped.
+    min_size: usize,
+    pub blobs: Vec<Vec<usize>>,
 }
 
-impl<const CHANNELS: usize> ColorBlobs<CHANNELS> {
-    pub fn new(raw: &[u8], img_width: usize) -> Self {
-        let mut previous_chunk = &[0; CHANNELS][..];
-        let mut pixels = Vec::with_capacity(raw.len() / CHANNELS);
-        for current in raw.chunks_exact(CHANNELS) {
-            pixels.push(img::Pixel::<CHANNELS>::compute_forward(
-                previous_chunk,
-                current,
-            ));
-            previous_chunk = current;
-        }
-
+impl<'a, const CHANNELS: usize> ColorBlobs<'a, CHANNELS> {
+    /// The pixels already visited are not part of any blob.
+    pub fn new(
+        pixels: &'a [img::Pixel<CHANNELS>],
+        img_width: usize,
+        is_visited: Vec<bool>,
+        min_size: usize,
+    ) -> Self {
         Self {
             img_width,
-            is_visited: vec![false; pixels.len()],
             pixels,
+            is_visited,
+            min_size,
             blobs: Vec::new(),
         }
     }
 
     pub fn compute(&mut self) {
         for idx in 0..self.pixels.len() {
-            self.visit(idx);
-            //self.pixels[idx].0 = [255; CHANNELS];
+            if !self.is_visited[idx] {
+                self.visit(idx);
+            }
         }
     }
 
     pub fn visit(&mut self, idx: usize) {
         let mut stack = vec![idx];
-        let mut blob = vec![idx];
+        let mut blob = Vec::new();
+        self.is_visited[idx] = true;
 
         while let Some(pixel_idx) = stack.pop() {
-            if self.is_visited[pixel_idx] {
-                continue;
-            }
-            self.is_visited[pixel_idx] = true;
-            for neighbor_idx in self.neighbors(pixel_idx) {
+            blob.push(pixel_idx);
+            for neighbor_idx in self.neighbors(pixel_idx).into_iter().flatten() {
                 if !self.is_visited[neighbor_idx] && self.pixels[neighbor_idx] == self.pixels[idx] {
+                    self.is_visited[neighbor_idx] = true;
                     stack.push(neighbor_idx);
-                    blob.push(neighbor_idx);
                 }
             }
         }
-        if blob.len() > 10 {
+        if blob.len() > self.min_size {
             self.blobs.push(blob);
         }
     }
 
-    pub fn neighbors(&self, idx: usize) -> Vec<usize> {
-        let mut neighbors = Vec::with_capacity(4);
+    /// Return the pixels on the left, on the right, above and below the pixel at `idx`, `None`
+    /// outside of the image.
+    #[inline]
+    pub fn neighbors(&self, idx: usize) -> [Option<usize>; 4] {
         let x = idx % self.img_width;
-        if x > 0 {
-            neighbors.push(idx - 1);
-        }
-        if x < self.img_width - 1 {
-            neighbors.push(idx + 1);
-        }
-        if idx > self.img_width {
-            neighbors.push(idx - self.img_width);
-        }
-        if idx < self.pixels.len() - self.img_width - 1 {
-            neighbors.push(idx + self.img_width);
-        }
-        neighbors
+        [
+            (x > 0).then(|| idx - 1),
+            (x + 1 < self.img_width).then_some(idx + 1),
+            idx.checked_sub(self.img_width),
+            Some(idx + self.img_width).filter(|below| *below < self.pixels.len()),
+        ]
     }
 }
@@ -1,11 +1,20 @@
+use crate::blocks;
+use crate::encoder;
+
 pub struct Logger {
     pub repeating: usize,
     pub short: usize,
     pub medium: usize,
     pub long: usize,
+    pub wide: usize,
     pub offset: usize,
     pub palette: usize,
     pub gray: usize,
+    pub blob: usize,
+    pub region: usize,
+    /// The pixels looked up in the cache of the offsets and how many were found.
+    pub offset_lookups: usize,
+    pub offset_hits: usize,
 }
 
 impl Logger {
@@ -15,9 +24,22 @@ impl Logger {
             short: 0,
             medium: 0,
             long: 0,
+            wide: 0,
             offset: 0,
             palette: 0,
             gray: 0,
+            blob: 0,
+            region: 0,
+            offset_lookups: 0,
+            offset_hits: 0,
+        }
+    }
+
+    /// Return the share of the lookups in the cache of the offsets that found the pixel.
+    pub fn offset_hit_rate(&self) -> f64 {
+        match self.offset_lookups {
+            0 => 0.0,
+            lookups => self.offset_hits as f64 / lookups as f64,
         }
     }
 
@@ -26,9 +48,34 @@ impl Logger {
             + self.short
             + self.medium
             + self.long
+            + self.wide
             + self.offset
             + self.palette
             + self.gray
+            + self.blob
+            + self.region
+    }
+}
+
+impl encoder::Observer for Logger {
+    fn block<const CHANNELS: usize>(&mut self, typ: blocks::Typ<CHANNELS>, _: &blocks::Block) {
+        match typ {
+            blocks::Typ::Repeating => self.repeating += 1,
+            blocks::Typ::Short => self.short += 1,
+            blocks::Typ::Medium => self.medium += 1,
+            blocks::Typ::Long => self.long += 1,
+            blocks::Typ::Wide => self.wide += 1,
+            blocks::Typ::Offset => self.offset += 1,
+            blocks::Typ::Color => self.palette += 1,
+            blocks::Typ::Gray => self.gray += 1,
+            blocks::Typ::Blob => self.blob += 1,
+            blocks::Typ::Region => self.region += 1,
+        }
+    }
+
+    fn offset(&mut self, hit: bool) {
+        self.offset_lookups += 1;
+        self.offset_hits += hit as usize;
     }
 }
 
@@ -37,9 +84,15 @@ pub struct Agregator {
     pub short: f64,
     pub medium: f64,
     pub long: f64,
+    pub wide: f64,
     pub offset: f64,
     pub palette: f64,
     pub gray: f64,
+    pub blob: f64,
+    pub region: f64,
+    /// The sum of the hit rates of the offsets of the images, then their average.
+    pub offset_hit_rate: f64,
+    images: usize,
 }
 
 impl Agregator {
@@ -49,9 +102,14 @@ impl Agregator {
             short: 0.0,
             medium: 0.0,
             long: 0.0,
+            wide: 0.0,
             offset: 0.0,
             palette: 0.0,
             gray: 0.0,
+            blob: 0.0,
+            region: 0.0,
+            offset_hit_rate: 0.0,
+            images: 0,
         }
     }
 
@@ -60,9 +118,12 @@ impl Agregator {
             + self.short
             + self.medium
             + self.long
+            + self.wide
             + self.offset
             + self.palette
             + self.gray
+            + self.blob
+            + self.region
     }
 
     pub fn add(&mut self, logger: &Logger) {
@@ -71,20 +132,29 @@ impl Agregator {
         self.short += logger.short as f64 / total;
         self.medium += logger.medium as f64 / total;
         self.long += logger.long as f64 / total;
+        self.wide += logger.wide as f64 / total;
         self.offset += logger.offset as f64 / total;
         self.palette += logger.palette as f64 / total;
         self.gray += logger.gray as f64 / total;
+        self.blob += logger.blob as f64 / total;
+        self.region += logger.region as f64 / total;
+        self.offset_hit_rate += logger.offset_hit_rate();
+        self.images += 1;
     }
 
     pub fn average(&mut self) {
         let total = self.total();
-        self.repeating = self.repeating / total;
-        self.short = self.short / total;
-        self.medium = self.medium / total;
-        self.long = self.long / total;
-        self.offset = self.offset / total;
-        self.palette = self.palette / total;
-        self.gray = self.gray / total;
+        self.repeating /= total;
+        self.short /= total;
+        self.medium /= total;
+        self.long /= total;
+        self.wide /= total;
+        self.offset /= total;
+        self.palette /= total;
+        self.gray /= total;
+        self.blob /= total;
+        self.region /= total;
+        self.offset_hit_rate /= self.images.max(1) as f64;
     }
 }
 
@@ -92,14 +162,18 @@ impl std::fmt::Display for Agregator {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "repeating: {}\nshort: {}\nmedium: {}\nlong: {}\noffset: {}\npalette: {}\ngray: {}",
+            "repeating: {}\nshort: {}\nmedium: {}\nlong: {}\nwide: {}\noffset: {}\npalette: {}\ngray: {}\nblob: {}\nregion: {}\noffset hit rate: {}",
             self.repeating,
             self.short,
             self.medium,
             self.long,
+            self.wide,
             self.offset,
             self.palette,
-            self.gray
+            self.gray,
+            self.blob,
+            self.region,
+            self.offset_hit_rate
         )
     }
 }
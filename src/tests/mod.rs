@@ -1,8 +1,6 @@
 pub mod log;
-use crate::encoder;
+use crate::{encoder, img, metadata};
 use colored::*;
-use png;
-use qoi;
 use std::collections::HashMap;
 use std::time::Instant;
 
@@ -220,11 +218,17 @@ impl TestImage {
 
     pub fn test_boi_with_log(&mut self) {
         let mut test = Test::start_with_ref(Algo::Boi, &self.tests[0]);
-        let (logger, encoded) = if !self.is_alpha {
-            encoder::Encoder::<3>::encode_with_logger(&self.bytes, self.width, self.height)
-        } else {
-            encoder::Encoder::<4>::encode_with_logger(&self.bytes, self.width, self.height)
-        };
+        let channels = if self.is_alpha { 4 } else { 3 };
+        let info = img::ImageInfo::new(self.width as u32, self.height as u32, channels);
+        let mut logger = log::Logger::new();
+        let encoded = encoder::encode_with_observer(
+            &self.bytes,
+            &info,
+            &metadata::Metadata::default(),
+            &encoder::EncoderOptions::default(),
+            &mut logger,
+        )
+        .unwrap();
         let len = encoded.len();
         let _ = std::fs::write("./img/out.boi", encoded);
         test.stop(len);
@@ -234,11 +238,9 @@ impl TestImage {
 
     pub fn test_boi(&mut self) {
         let mut test = Test::start_with_ref(Algo::Boi, &self.tests[0]);
-        let encoded = if !self.is_alpha {
-            encoder::Encoder::<3>::encode(&self.bytes, self.width, self.height)
-        } else {
-            encoder::Encoder::<4>::encode(&self.bytes, self.width, self.height)
-        };
+        let channels = if self.is_alpha { 4 } else { 3 };
+        let encoded =
+            encoder::encode(&self.bytes, self.width as u32, self.height as u32, channels).unwrap();
         let len = encoded.len();
         let _ = std::fs::write("./img/out.boi", encoded);
         test.stop(len);
@@ -271,7 +273,7 @@ impl TestImage {
         let mut test = Test::start(Algo::Png);
         let path = std::path::Path::new("./img/out.png");
         let file = std::fs::File::create(path).unwrap();
-        let ref mut w = std::io::BufWriter::new(file);
+        let w = &mut std::io::BufWriter::new(file);
 
         let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
         match self.is_alpha {